@@ -1,5 +1,4 @@
 use std::fs::File;
-use std::io::Write;
 use std::path::PathBuf;
 use std::sync::Arc;
 
@@ -39,9 +38,10 @@ fn main() {
     debug!("read headers: {:?}", demuxer.read_headers().unwrap());
     debug!("global info: {:#?}", demuxer.info);
 
-    let mut output = File::create(opt.output).unwrap();
+    let output = File::create(opt.output).unwrap();
 
-    let mut muxer = muxer::Context::new(MkvMuxer::matroska(), Writer::new(Vec::new()));
+    let mut mkv = MkvMuxer::matroska();
+    let mut muxer = muxer::Context::new(&mut mkv, Writer::new(output));
     muxer.configure().unwrap();
     muxer.set_global_info(demuxer.info.clone()).unwrap();
     muxer.write_header().unwrap();
@@ -74,5 +74,6 @@ fn main() {
         }
     }
 
-    output.write_all(muxer.writer().as_ref().0).unwrap();
+    let mut writer = muxer.into_writer();
+    mkv.finalize(&mut writer).unwrap();
 }
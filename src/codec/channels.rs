@@ -0,0 +1,141 @@
+//! Channel layouts of the audio codecs.
+
+use av_data::audiosample::{ChannelMap, ChannelType};
+
+use self::ChannelType::*;
+
+/// Speaker positions of the WAVEFORMATEXTENSIBLE channel mask, in bit order.
+const MASK_CHANNELS: [ChannelType; 18] = [
+    L, R, C, LFE, Ls, Rs, Lc, Rc, Cs, Lss, Rss, Ov, Lh, Ch, Rh, Lhs, Chs, Rhs,
+];
+
+/// Builds the channel map of a WAVEFORMATEXTENSIBLE channel mask.
+pub fn mask_channel_map(mask: u32) -> ChannelMap {
+    let mut map = ChannelMap::new();
+
+    for (bit, &ch) in MASK_CHANNELS.iter().enumerate() {
+        if mask & (1 << bit) != 0 {
+            map.add_channel(ch);
+        }
+    }
+
+    map
+}
+
+/// Computes the WAVEFORMATEXTENSIBLE channel mask of a channel map.
+///
+/// Returns `None` if some channel has no speaker position in the mask,
+/// or if the channels are not in the mask order.
+pub fn channel_mask(map: &ChannelMap) -> Option<u32> {
+    let mut mask = 0u32;
+
+    for i in 0..map.len() {
+        let bit = MASK_CHANNELS
+            .iter()
+            .position(|&ch| ch == map.get_channel(i))?;
+        if mask >> bit != 0 {
+            return None;
+        }
+        mask |= 1 << bit;
+    }
+
+    Some(mask)
+}
+
+/// Builds the usual channel map for a channel count, in the WAVEFORMATEXTENSIBLE order.
+///
+/// This is also the channel order of FLAC and PCM.
+pub fn default_channel_map(count: usize) -> Option<ChannelMap> {
+    let mask = match count {
+        1 => 0x4,
+        2 => 0x3,
+        3 => 0x7,
+        4 => 0x33,
+        5 => 0x37,
+        6 => 0x3F,
+        7 => 0x70F,
+        8 => 0x63F,
+        n if n <= MASK_CHANNELS.len() => (1 << n) - 1,
+        _ => return None,
+    };
+
+    Some(mask_channel_map(mask))
+}
+
+/// Builds the channel map of Vorbis and of the Opus mapping families 0 and 1.
+pub fn vorbis_channel_map(count: usize) -> Option<ChannelMap> {
+    let channels: &[ChannelType] = match count {
+        1 => &[C],
+        2 => &[L, R],
+        3 => &[L, C, R],
+        4 => &[L, R, Ls, Rs],
+        5 => &[L, C, R, Ls, Rs],
+        6 => &[L, C, R, Ls, Rs, LFE],
+        7 => &[L, C, R, Lss, Rss, Cs, LFE],
+        8 => &[L, C, R, Lss, Rss, Ls, Rs, LFE],
+        _ => return default_channel_map(count),
+    };
+
+    let mut map = ChannelMap::new();
+    map.add_channels(channels);
+    Some(map)
+}
+
+/// Builds the channel map of an AAC channel configuration.
+///
+/// The configuration 0 means the layout is defined by a program config element.
+pub fn aac_channel_map(channel_config: u8) -> Option<ChannelMap> {
+    let channels: &[ChannelType] = match channel_config {
+        1 => &[C],
+        2 => &[L, R],
+        3 => &[C, L, R],
+        4 => &[C, L, R, Cs],
+        5 => &[C, L, R, Ls, Rs],
+        6 => &[C, L, R, Ls, Rs, LFE],
+        7 => &[C, Lc, Rc, L, R, Ls, Rs, LFE],
+        11 => &[C, L, R, Ls, Rs, Cs, LFE],
+        12 => &[C, L, R, Lss, Rss, Ls, Rs, LFE],
+        _ => return None,
+    };
+
+    let mut map = ChannelMap::new();
+    map.add_channels(channels);
+    Some(map)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn channel_masks() {
+        let map = mask_channel_map(0x60F);
+        assert_eq!(map.len(), 6);
+        assert_eq!(map.get_channel(3), LFE);
+        assert_eq!(map.get_channel(4), Lss);
+        assert_eq!(channel_mask(&map), Some(0x60F));
+
+        for count in 1..=18 {
+            let map = default_channel_map(count).unwrap();
+            assert_eq!(map.len(), count);
+            assert!(channel_mask(&map).is_some());
+        }
+        assert!(default_channel_map(19).is_none());
+
+        // Not in the mask order
+        let map = vorbis_channel_map(6).unwrap();
+        assert_eq!(channel_mask(&map), None);
+    }
+
+    #[test]
+    fn codec_layouts() {
+        assert_eq!(vorbis_channel_map(2).unwrap().get_channel(0), L);
+        assert_eq!(vorbis_channel_map(8).unwrap().len(), 8);
+        assert_eq!(vorbis_channel_map(10).unwrap().len(), 10);
+
+        assert_eq!(aac_channel_map(1).unwrap().get_channel(0), C);
+        assert_eq!(aac_channel_map(7).unwrap().len(), 8);
+        assert_eq!(aac_channel_map(11).unwrap().len(), 7);
+        assert!(aac_channel_map(0).is_none());
+    }
+}
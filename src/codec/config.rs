@@ -0,0 +1,666 @@
+//! Parsers for the CodecPrivate of the common codecs.
+
+use nom::{
+    bytes::complete::{tag, take},
+    combinator::{map, verify},
+    multi::count,
+    number::complete::{be_u16, be_u24, be_u32, be_u8, le_i16, le_i32, le_u16, le_u32, le_u8},
+    sequence::tuple,
+    IResult,
+};
+
+use av_data::audiosample::ChannelMap;
+
+use crate::codec::channels::{aac_channel_map, default_channel_map, vorbis_channel_map};
+use crate::elements::{laced_frames, Lacing};
+
+/// Decoder configuration stored in the CodecPrivate of a track.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CodecConfig {
+    Opus(OpusHead),
+    Vorbis(VorbisHeader),
+    Theora(TheoraHeader),
+    Flac(FlacStreamInfo),
+    Aac(AudioSpecificConfig),
+    Avc(AvcConfig),
+    Hevc(HevcConfig),
+    Av1(Av1Config),
+    Vp9(Vp9Features),
+}
+
+impl CodecConfig {
+    /// Parses the CodecPrivate of a codec, identified by its av-data name.
+    pub fn parse(codec: &str, data: &[u8]) -> Option<CodecConfig> {
+        let config = match codec {
+            "opus" => OpusHead::parse(data)
+                .ok()
+                .map(|(_, c)| CodecConfig::Opus(c)),
+            "vorbis" => xiph_headers(data)
+                .and_then(|h| VorbisHeader::parse(h[0]).ok())
+                .map(|(_, c)| CodecConfig::Vorbis(c)),
+            "theora" => xiph_headers(data)
+                .and_then(|h| TheoraHeader::parse(h[0]).ok())
+                .map(|(_, c)| CodecConfig::Theora(c)),
+            "flac" => FlacStreamInfo::parse(data)
+                .ok()
+                .map(|(_, c)| CodecConfig::Flac(c)),
+            "aac" => AudioSpecificConfig::parse(data).map(CodecConfig::Aac),
+            "h264" => AvcConfig::parse(data)
+                .ok()
+                .map(|(_, c)| CodecConfig::Avc(c)),
+            "hevc" => HevcConfig::parse(data)
+                .ok()
+                .map(|(_, c)| CodecConfig::Hevc(c)),
+            "av1" => Av1Config::parse(data)
+                .ok()
+                .map(|(_, c)| CodecConfig::Av1(c)),
+            "vp9" => Vp9Features::parse(data)
+                .ok()
+                .map(|(_, c)| CodecConfig::Vp9(c)),
+            _ => return None,
+        };
+
+        if config.is_none() {
+            log::warn!("invalid {codec} CodecPrivate");
+        }
+
+        config
+    }
+
+    /// Returns the channel layout of the audio codecs.
+    pub fn channel_map(&self) -> Option<ChannelMap> {
+        match self {
+            CodecConfig::Opus(head) => match head.mapping_family {
+                0 | 1 => vorbis_channel_map(usize::from(head.channels)),
+                _ => default_channel_map(usize::from(head.channels)),
+            },
+            CodecConfig::Vorbis(header) => vorbis_channel_map(usize::from(header.channels)),
+            CodecConfig::Flac(info) => default_channel_map(usize::from(info.channels)),
+            CodecConfig::Aac(asc) => aac_channel_map(asc.channel_config),
+            _ => None,
+        }
+    }
+}
+
+/// Splits the Xiph-laced identification, comment and setup headers of Vorbis and Theora.
+pub fn xiph_headers(data: &[u8]) -> Option<Vec<&[u8]>> {
+    match laced_frames(&Lacing::Xiph, data) {
+        Ok((_, headers)) if headers.len() == 3 => Some(headers),
+        _ => None,
+    }
+}
+
+/// Opus identification header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OpusHead {
+    pub version: u8,
+    pub channels: u8,
+    /// Samples at 48 kHz to discard from the decoder output
+    pub pre_skip: u16,
+    pub input_sample_rate: u32,
+    /// Gain in Q7.8 dB
+    pub output_gain: i16,
+    pub mapping_family: u8,
+    /// Stream count, coupled stream count and channel mapping,
+    /// missing for the mapping family 0
+    pub mapping: Option<(u8, u8, Vec<u8>)>,
+}
+
+impl OpusHead {
+    pub fn parse(input: &[u8]) -> IResult<&[u8], OpusHead> {
+        let (i, (_, version, channels, pre_skip, input_sample_rate, output_gain, mapping_family)) =
+            tuple((tag("OpusHead"), le_u8, le_u8, le_u16, le_u32, le_i16, le_u8))(input)?;
+
+        let (i, mapping) = if mapping_family == 0 {
+            (i, None)
+        } else {
+            let (i, (streams, coupled, mapping)) =
+                tuple((le_u8, le_u8, take(channels as usize)))(i)?;
+            (i, Some((streams, coupled, mapping.to_vec())))
+        };
+
+        Ok((
+            i,
+            OpusHead {
+                version,
+                channels,
+                pre_skip,
+                input_sample_rate,
+                output_gain,
+                mapping_family,
+                mapping,
+            },
+        ))
+    }
+}
+
+/// Vorbis identification header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct VorbisHeader {
+    pub version: u32,
+    pub channels: u8,
+    pub sample_rate: u32,
+    pub bitrate_maximum: i32,
+    pub bitrate_nominal: i32,
+    pub bitrate_minimum: i32,
+    /// Short and long block sizes, as powers of two
+    pub blocksizes: (u8, u8),
+}
+
+impl VorbisHeader {
+    pub fn parse(input: &[u8]) -> IResult<&[u8], VorbisHeader> {
+        let (i, (_, version, channels, sample_rate)) =
+            tuple((tag(b"\x01vorbis"), le_u32, le_u8, le_u32))(input)?;
+        let (i, (bitrate_maximum, bitrate_nominal, bitrate_minimum, blocksizes)) =
+            tuple((le_i32, le_i32, le_i32, le_u8))(i)?;
+
+        Ok((
+            i,
+            VorbisHeader {
+                version,
+                channels,
+                sample_rate,
+                bitrate_maximum,
+                bitrate_nominal,
+                bitrate_minimum,
+                blocksizes: (blocksizes & 0x0F, blocksizes >> 4),
+            },
+        ))
+    }
+}
+
+/// Theora identification header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TheoraHeader {
+    pub version: (u8, u8, u8),
+    /// Size of the frame, in macroblocks
+    pub frame_size: (u16, u16),
+    pub picture_width: u32,
+    pub picture_height: u32,
+    pub picture_offset: (u8, u8),
+    pub frame_rate: (u32, u32),
+    pub aspect_ratio: (u32, u32),
+    pub colour_space: u8,
+    pub nominal_bitrate: u32,
+    pub quality: u8,
+    pub keyframe_granule_shift: u8,
+    pub pixel_format: u8,
+}
+
+impl TheoraHeader {
+    pub fn parse(input: &[u8]) -> IResult<&[u8], TheoraHeader> {
+        let (i, (_, vmaj, vmin, vrev, fmbw, fmbh, picw, pich, picx, picy)) = tuple((
+            tag(b"\x80theora"),
+            be_u8,
+            be_u8,
+            be_u8,
+            be_u16,
+            be_u16,
+            be_u24,
+            be_u24,
+            be_u8,
+            be_u8,
+        ))(input)?;
+        let (i, (frn, frd, parn, pard, cs, nombr, flags)) =
+            tuple((be_u32, be_u32, be_u24, be_u24, be_u8, be_u24, be_u16))(i)?;
+
+        Ok((
+            i,
+            TheoraHeader {
+                version: (vmaj, vmin, vrev),
+                frame_size: (fmbw, fmbh),
+                picture_width: picw,
+                picture_height: pich,
+                picture_offset: (picx, picy),
+                frame_rate: (frn, frd),
+                aspect_ratio: (parn, pard),
+                colour_space: cs,
+                nominal_bitrate: nombr,
+                quality: (flags >> 10) as u8,
+                keyframe_granule_shift: ((flags >> 5) & 0x1F) as u8,
+                pixel_format: ((flags >> 3) & 0x03) as u8,
+            },
+        ))
+    }
+}
+
+/// FLAC STREAMINFO metadata block.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FlacStreamInfo {
+    pub min_block_size: u16,
+    pub max_block_size: u16,
+    pub min_frame_size: u32,
+    pub max_frame_size: u32,
+    pub sample_rate: u32,
+    pub channels: u8,
+    pub bits_per_sample: u8,
+    pub total_samples: u64,
+    pub md5: [u8; 16],
+}
+
+impl FlacStreamInfo {
+    /// Parses the STREAMINFO block following the `fLaC` signature.
+    pub fn parse(input: &[u8]) -> IResult<&[u8], FlacStreamInfo> {
+        // The STREAMINFO is the first metadata block, and is 34 bytes long
+        let (i, _) = tuple((
+            tag("fLaC"),
+            verify(be_u8, |t| t & 0x7F == 0),
+            verify(be_u24, |l| *l == 34),
+        ))(input)?;
+        let (i, (min_block_size, max_block_size, min_frame_size, max_frame_size)) =
+            tuple((be_u16, be_u16, be_u24, be_u24))(i)?;
+        let (i, (fields, md5)) = tuple((take(8usize), take(16usize)))(i)?;
+
+        let fields = u64::from_be_bytes(fields.try_into().unwrap());
+
+        Ok((
+            i,
+            FlacStreamInfo {
+                min_block_size,
+                max_block_size,
+                min_frame_size,
+                max_frame_size,
+                sample_rate: (fields >> 44) as u32,
+                channels: ((fields >> 41) & 0x07) as u8 + 1,
+                bits_per_sample: ((fields >> 36) & 0x1F) as u8 + 1,
+                total_samples: fields & 0xF_FFFF_FFFF,
+                md5: md5.try_into().unwrap(),
+            },
+        ))
+    }
+}
+
+/// MPEG-4 AudioSpecificConfig.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AudioSpecificConfig {
+    /// Audio Object Type, the AAC profile
+    pub object_type: u8,
+    pub sample_rate: u32,
+    /// The channel configuration, 0 if defined by a program config element
+    pub channel_config: u8,
+    /// Output sample rate of the SBR extension, if signaled explicitly
+    pub extension_sample_rate: Option<u32>,
+}
+
+const AAC_SAMPLE_RATES: [u32; 13] = [
+    96000, 88200, 64000, 48000, 44100, 32000, 24000, 22050, 16000, 12000, 11025, 8000, 7350,
+];
+
+impl AudioSpecificConfig {
+    pub fn parse(input: &[u8]) -> Option<AudioSpecificConfig> {
+        let mut bits = BitReader::new(input);
+
+        let object_type = |bits: &mut BitReader| match bits.read(5)? {
+            31 => Some(32 + bits.read(6)? as u8),
+            t => Some(t as u8),
+        };
+        let sample_rate = |bits: &mut BitReader| match bits.read(4)? {
+            15 => bits.read(24),
+            index => AAC_SAMPLE_RATES.get(index as usize).copied(),
+        };
+
+        let mut config = AudioSpecificConfig {
+            object_type: object_type(&mut bits)?,
+            sample_rate: sample_rate(&mut bits)?,
+            channel_config: bits.read(4)? as u8,
+            extension_sample_rate: None,
+        };
+
+        // Explicit SBR (5) and PS (29) signaling, followed by the core object type
+        if config.object_type == 5 || config.object_type == 29 {
+            config.extension_sample_rate = Some(sample_rate(&mut bits)?);
+            config.object_type = object_type(&mut bits)?;
+        }
+
+        Some(config)
+    }
+}
+
+/// AVCDecoderConfigurationRecord, the H.264 CodecPrivate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AvcConfig {
+    pub profile: u8,
+    pub profile_compatibility: u8,
+    pub level: u8,
+    /// Size of the NAL unit lengths in the frames
+    pub nal_length_size: u8,
+    pub sps: Vec<Vec<u8>>,
+    pub pps: Vec<Vec<u8>>,
+}
+
+impl AvcConfig {
+    pub fn parse(input: &[u8]) -> IResult<&[u8], AvcConfig> {
+        let (i, (_, profile, profile_compatibility, level, length_size)) =
+            tuple((verify(be_u8, |v| *v == 1), be_u8, be_u8, be_u8, be_u8))(input)?;
+
+        let (i, sps_count) = be_u8(i)?;
+        let (i, sps) = count(nal_unit, usize::from(sps_count & 0x1F))(i)?;
+        let (i, pps_count) = be_u8(i)?;
+        let (i, pps) = count(nal_unit, usize::from(pps_count))(i)?;
+
+        Ok((
+            i,
+            AvcConfig {
+                profile,
+                profile_compatibility,
+                level,
+                nal_length_size: (length_size & 0x03) + 1,
+                sps,
+                pps,
+            },
+        ))
+    }
+}
+
+/// HEVCDecoderConfigurationRecord, the H.265 CodecPrivate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HevcConfig {
+    pub profile_space: u8,
+    pub tier: u8,
+    pub profile: u8,
+    pub profile_compatibility: u32,
+    pub constraint_indicator: u64,
+    pub level: u8,
+    pub chroma_format: u8,
+    pub bit_depth_luma: u8,
+    pub bit_depth_chroma: u8,
+    /// Size of the NAL unit lengths in the frames
+    pub nal_length_size: u8,
+    /// The VPS, SPS, PPS and SEI NAL units, by NAL unit type
+    pub nal_arrays: Vec<(u8, Vec<Vec<u8>>)>,
+}
+
+impl HevcConfig {
+    pub fn parse(input: &[u8]) -> IResult<&[u8], HevcConfig> {
+        let (i, (_, profile, profile_compatibility, constraint, level)) = tuple((
+            verify(be_u8, |v| *v == 1),
+            be_u8,
+            be_u32,
+            take(6usize),
+            be_u8,
+        ))(input)?;
+        let (i, (_, _, chroma_format, bit_depth_luma, bit_depth_chroma, _, flags)) =
+            tuple((be_u16, be_u8, be_u8, be_u8, be_u8, be_u16, be_u8))(i)?;
+
+        let nal_array = map(
+            tuple((be_u8, |i| {
+                let (i, n) = be_u16(i)?;
+                count(nal_unit, usize::from(n))(i)
+            })),
+            |(nal_type, units)| (nal_type & 0x3F, units),
+        );
+        let (i, arrays) = be_u8(i)?;
+        let (i, nal_arrays) = count(nal_array, usize::from(arrays))(i)?;
+
+        let constraint = constraint
+            .iter()
+            .fold(0, |acc, b| (acc << 8) | u64::from(*b));
+
+        Ok((
+            i,
+            HevcConfig {
+                profile_space: profile >> 6,
+                tier: (profile >> 5) & 0x01,
+                profile: profile & 0x1F,
+                profile_compatibility,
+                constraint_indicator: constraint,
+                level,
+                chroma_format: chroma_format & 0x03,
+                bit_depth_luma: (bit_depth_luma & 0x07) + 8,
+                bit_depth_chroma: (bit_depth_chroma & 0x07) + 8,
+                nal_length_size: (flags & 0x03) + 1,
+                nal_arrays,
+            },
+        ))
+    }
+}
+
+// A NAL unit, preceded by its 16 bits size
+fn nal_unit(input: &[u8]) -> IResult<&[u8], Vec<u8>> {
+    let (i, size) = be_u16(input)?;
+    map(take(size), <[u8]>::to_vec)(i)
+}
+
+/// AV1CodecConfigurationRecord, the AV1 CodecPrivate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Av1Config {
+    pub profile: u8,
+    pub level: u8,
+    pub tier: u8,
+    pub bit_depth: u8,
+    pub monochrome: bool,
+    pub chroma_subsampling: (u8, u8),
+    pub chroma_sample_position: u8,
+    pub initial_presentation_delay: Option<u8>,
+    /// The Sequence Header OBU and the Metadata OBUs
+    pub config_obus: Vec<u8>,
+}
+
+impl Av1Config {
+    pub fn parse(input: &[u8]) -> IResult<&[u8], Av1Config> {
+        let (i, (_, profile_level, flags, delay)) =
+            tuple((verify(be_u8, |v| *v == 0x81), be_u8, be_u8, be_u8))(input)?;
+
+        let bit_depth = match (flags & 0x40 != 0, flags & 0x20 != 0) {
+            (true, true) => 12,
+            (true, false) => 10,
+            _ => 8,
+        };
+
+        Ok((
+            &i[i.len()..],
+            Av1Config {
+                profile: profile_level >> 5,
+                level: profile_level & 0x1F,
+                tier: flags >> 7,
+                bit_depth,
+                monochrome: flags & 0x10 != 0,
+                chroma_subsampling: ((flags >> 3) & 0x01, (flags >> 2) & 0x01),
+                chroma_sample_position: flags & 0x03,
+                initial_presentation_delay: (delay & 0x10 != 0).then_some((delay & 0x0F) + 1),
+                config_obus: i.to_vec(),
+            },
+        ))
+    }
+}
+
+/// VP9 codec features, stored as ID, length and value triples.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Vp9Features {
+    pub profile: Option<u8>,
+    pub level: Option<u8>,
+    pub bit_depth: Option<u8>,
+    pub chroma_subsampling: Option<u8>,
+}
+
+impl Vp9Features {
+    pub fn parse(input: &[u8]) -> IResult<&[u8], Vp9Features> {
+        let mut features = Vp9Features::default();
+
+        let mut i = input;
+        while !i.is_empty() {
+            let (rest, (id, value)) = tuple((be_u8, |i| {
+                let (i, len) = be_u8(i)?;
+                take(len)(i)
+            }))(i)?;
+            i = rest;
+
+            // Every known feature is 1 byte long
+            let value = match value {
+                [v] => Some(*v),
+                _ => continue,
+            };
+            match id {
+                1 => features.profile = value,
+                2 => features.level = value,
+                3 => features.bit_depth = value,
+                4 => features.chroma_subsampling = value,
+                _ => {}
+            }
+        }
+
+        Ok((i, features))
+    }
+}
+
+// Reads big-endian bit fields
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader { data, pos: 0 }
+    }
+
+    fn read(&mut self, bits: usize) -> Option<u32> {
+        let mut v = 0;
+        for _ in 0..bits {
+            let byte = self.data.get(self.pos / 8)?;
+            v = (v << 1) | u32::from((byte >> (7 - self.pos % 8)) & 1);
+            self.pos += 1;
+        }
+        Some(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn opus_head() {
+        let webm = include_bytes!("../../assets/bbb-vp9-opus.webm");
+        let start = webm.windows(8).position(|w| w == b"OpusHead").unwrap();
+
+        let head = match CodecConfig::parse("opus", &webm[start..start + 19]) {
+            Some(CodecConfig::Opus(head)) => head,
+            c => panic!("unexpected config {c:?}"),
+        };
+        assert_eq!(head.channels, 1);
+        assert_eq!(head.pre_skip, 312);
+        assert_eq!(head.input_sample_rate, 48000);
+        assert_eq!(head.mapping, None);
+    }
+
+    #[test]
+    fn audio_configs() {
+        // Xiph laced identification, comment and setup headers
+        #[rustfmt::skip]
+        let vorbis = [
+            0x02, 30, 1,
+            0x01, b'v', b'o', b'r', b'b', b'i', b's', 0, 0, 0, 0, 2, 0x44, 0xAC, 0, 0,
+            0, 0, 0, 0, 0x00, 0xF4, 0x01, 0x00, 0, 0, 0, 0, 0xB8, 0x01,
+            0x03,
+            0x05,
+        ];
+        match CodecConfig::parse("vorbis", &vorbis) {
+            Some(CodecConfig::Vorbis(h)) => {
+                assert_eq!((h.channels, h.sample_rate), (2, 44100));
+                assert_eq!(h.bitrate_nominal, 128000);
+                assert_eq!(h.blocksizes, (8, 11));
+            }
+            c => panic!("unexpected config {c:?}"),
+        }
+
+        #[rustfmt::skip]
+        let flac = [
+            b'f', b'L', b'a', b'C', 0x80, 0, 0, 34,
+            0x10, 0x00, 0x10, 0x00, 0, 0, 0x0E, 0, 0x2A, 0x51,
+            // 44100 Hz, 2 channels, 16 bits, 441000 samples
+            0x0A, 0xC4, 0x42, 0xF0, 0x00, 0x06, 0xBA, 0xA8,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+        ];
+        match CodecConfig::parse("flac", &flac) {
+            Some(CodecConfig::Flac(s)) => {
+                assert_eq!(
+                    (s.sample_rate, s.channels, s.bits_per_sample),
+                    (44100, 2, 16)
+                );
+                assert_eq!(s.total_samples, 441000);
+                assert_eq!(s.max_block_size, 4096);
+            }
+            c => panic!("unexpected config {c:?}"),
+        }
+
+        // AAC LC, 48 kHz, stereo
+        assert_eq!(
+            AudioSpecificConfig::parse(&[0x11, 0x90]),
+            Some(AudioSpecificConfig {
+                object_type: 2,
+                sample_rate: 48000,
+                channel_config: 2,
+                extension_sample_rate: None,
+            })
+        );
+
+        // HE-AAC with explicit SBR, 24 kHz core and 48 kHz output, stereo
+        assert_eq!(
+            AudioSpecificConfig::parse(&[0x2B, 0x11, 0x88, 0x00]),
+            Some(AudioSpecificConfig {
+                object_type: 2,
+                sample_rate: 24000,
+                channel_config: 2,
+                extension_sample_rate: Some(48000),
+            })
+        );
+    }
+
+    #[test]
+    fn video_configs() {
+        #[rustfmt::skip]
+        let avc = [
+            0x01, 0x64, 0x00, 0x1F, 0xFF,
+            0xE1, 0x00, 0x04, 0x67, 0x64, 0x00, 0x1F,
+            0x01, 0x00, 0x02, 0x68, 0xEB,
+        ];
+        assert_eq!(
+            CodecConfig::parse("h264", &avc),
+            Some(CodecConfig::Avc(AvcConfig {
+                profile: 100,
+                profile_compatibility: 0,
+                level: 31,
+                nal_length_size: 4,
+                sps: vec![vec![0x67, 0x64, 0x00, 0x1F]],
+                pps: vec![vec![0x68, 0xEB]],
+            }))
+        );
+
+        #[rustfmt::skip]
+        let hevc = [
+            0x01, 0x22, 0x20, 0x00, 0x00, 0x00, 0x90, 0x00, 0x00, 0x00, 0x00, 0x00,
+            0x5D, 0xF0, 0x00, 0xFC, 0xFD, 0xFA, 0xFA, 0x00, 0x00, 0x0F,
+            0x01, 0xA0, 0x00, 0x01, 0x00, 0x02, 0x40, 0x01,
+        ];
+        match CodecConfig::parse("hevc", &hevc) {
+            Some(CodecConfig::Hevc(c)) => {
+                assert_eq!((c.profile_space, c.tier, c.profile, c.level), (0, 1, 2, 93));
+                assert_eq!((c.chroma_format, c.bit_depth_luma), (1, 10));
+                assert_eq!(c.nal_length_size, 4);
+                assert_eq!(c.nal_arrays, vec![(32, vec![vec![0x40, 0x01]])]);
+            }
+            c => panic!("unexpected config {c:?}"),
+        }
+
+        match CodecConfig::parse("av1", &[0x81, 0x28, 0x4C, 0x00, 0x0A, 0x0B]) {
+            Some(CodecConfig::Av1(c)) => {
+                assert_eq!((c.profile, c.level, c.tier, c.bit_depth), (1, 8, 0, 10));
+                assert_eq!(c.chroma_subsampling, (1, 1));
+                assert_eq!(c.initial_presentation_delay, None);
+                assert_eq!(c.config_obus, vec![0x0A, 0x0B]);
+            }
+            c => panic!("unexpected config {c:?}"),
+        }
+
+        assert_eq!(
+            CodecConfig::parse("vp9", &[1, 1, 2, 2, 1, 31, 3, 1, 10, 4, 1, 1]),
+            Some(CodecConfig::Vp9(Vp9Features {
+                profile: Some(2),
+                level: Some(31),
+                bit_depth: Some(10),
+                chroma_subsampling: Some(1),
+            }))
+        );
+    }
+}
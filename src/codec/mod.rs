@@ -0,0 +1,406 @@
+//! Mapping between the Matroska codec IDs and the av-data codec names.
+
+pub mod channels;
+pub mod config;
+
+use nom::{
+    bytes::complete::take,
+    number::complete::{le_i32, le_u16, le_u32},
+    sequence::tuple,
+    IResult,
+};
+
+/// Matroska codec IDs and the matching av-data codec names.
+///
+/// A codec name may match several codec IDs, the first one is used when muxing.
+pub static CODEC_IDS: &[(&str, &str)] = &[
+    // Video
+    ("V_AV1", "av1"),
+    ("V_VP8", "vp8"),
+    ("V_VP9", "vp9"),
+    ("V_MPEG4/ISO/AVC", "h264"),
+    ("V_MPEGH/ISO/HEVC", "hevc"),
+    ("V_MPEG4/ISO/ASP", "mpeg4"),
+    ("V_MPEG4/ISO/SP", "mpeg4"),
+    ("V_MPEG4/ISO/AP", "mpeg4"),
+    ("V_MPEG1", "mpeg1video"),
+    ("V_MPEG2", "mpeg2video"),
+    ("V_THEORA", "theora"),
+    ("V_PRORES", "prores"),
+    ("V_FFV1", "ffv1"),
+    // Audio
+    ("A_OPUS", "opus"),
+    ("A_VORBIS", "vorbis"),
+    ("A_AAC", "aac"),
+    ("A_FLAC", "flac"),
+    ("A_AC3", "ac3"),
+    ("A_EAC3", "eac3"),
+    ("A_DTS", "dts"),
+    ("A_TRUEHD", "truehd"),
+    ("A_MPEG/L3", "mp3"),
+    ("A_MPEG/L2", "mp2"),
+    ("A_PCM/INT/LIT", "pcm_int_le"),
+    ("A_PCM/INT/BIG", "pcm_int_be"),
+    ("A_PCM/FLOAT/IEEE", "pcm_float"),
+    ("A_ALAC", "alac"),
+    // Subtitles
+    ("S_TEXT/UTF8", "subrip"),
+    ("S_TEXT/ASCII", "subrip"),
+    ("S_TEXT/SSA", "ssa"),
+    ("S_TEXT/ASS", "ass"),
+    ("S_TEXT/WEBVTT", "webvtt"),
+    ("D_WEBVTT/SUBTITLES", "webvtt"),
+    ("S_TEXT/USF", "usf"),
+    ("S_HDMV/PGS", "pgs"),
+    ("S_VOBSUB", "dvdsub"),
+];
+
+/// FourCCs of the V_MS/VFW/FOURCC and V_QUICKTIME tracks, and the matching
+/// av-data codec names.
+pub static FOURCCS: &[(&[u8; 4], &str)] = &[
+    (b"avc1", "h264"),
+    (b"H264", "h264"),
+    (b"X264", "h264"),
+    (b"hvc1", "hevc"),
+    (b"hev1", "hevc"),
+    (b"HEVC", "hevc"),
+    (b"FMP4", "mpeg4"),
+    (b"XVID", "mpeg4"),
+    (b"DIVX", "mpeg4"),
+    (b"DX50", "mpeg4"),
+    (b"MP4V", "mpeg4"),
+    (b"MPG1", "mpeg1video"),
+    (b"MPG2", "mpeg2video"),
+    (b"MJPG", "mjpeg"),
+    (b"WMV3", "wmv3"),
+    (b"WVC1", "vc1"),
+    (b"VP80", "vp8"),
+    (b"VP90", "vp9"),
+    (b"AV01", "av1"),
+    (b"FFV1", "ffv1"),
+    (b"SVQ1", "svq1"),
+    (b"SVQ3", "svq3"),
+    (b"apch", "prores"),
+    (b"apcn", "prores"),
+    (b"apcs", "prores"),
+    (b"apco", "prores"),
+    (b"ap4h", "prores"),
+];
+
+/// WAVEFORMATEX format tags of the A_MS/ACM tracks, and the matching av-data
+/// codec names.
+pub static FORMAT_TAGS: &[(u16, &str)] = &[
+    (0x0001, "pcm_int_le"),
+    (0x0002, "adpcm_ms"),
+    (0x0003, "pcm_float"),
+    (0x0006, "pcm_alaw"),
+    (0x0007, "pcm_mulaw"),
+    (0x0011, "adpcm_ima_wav"),
+    (0x0050, "mp2"),
+    (0x0055, "mp3"),
+    (0x00FF, "aac"),
+    (0x706D, "aac"),
+    (0x0160, "wmav1"),
+    (0x0161, "wmav2"),
+    (0x0162, "wmapro"),
+    (0x0163, "wmalossless"),
+    (0x2000, "ac3"),
+    (0x2001, "dts"),
+    (0xF1AC, "flac"),
+];
+
+/// Returns the av-data codec name matching a Matroska codec ID.
+pub fn codec_name(codec_id: &str) -> Option<&'static str> {
+    CODEC_IDS
+        .iter()
+        .find(|(id, _)| *id == codec_id)
+        .map(|(_, name)| *name)
+        .or_else(|| {
+            // The profile may be part of the AAC codec ID, as in A_AAC/MPEG4/LC
+            codec_id.starts_with("A_AAC/").then_some("aac")
+        })
+}
+
+/// Returns the Matroska codec ID matching an av-data codec name.
+pub fn codec_id(name: &str) -> Option<&'static str> {
+    CODEC_IDS
+        .iter()
+        .find(|(_, n)| *n == name)
+        .map(|(id, _)| *id)
+}
+
+/// Returns the av-data codec name matching a FourCC.
+pub fn fourcc_codec_name(fourcc: &[u8]) -> Option<&'static str> {
+    FOURCCS
+        .iter()
+        .find(|(f, _)| f.eq_ignore_ascii_case(fourcc))
+        .map(|(_, name)| *name)
+}
+
+/// Returns the FourCC matching an av-data codec name.
+pub fn codec_fourcc(name: &str) -> Option<[u8; 4]> {
+    FOURCCS
+        .iter()
+        .find(|(_, n)| *n == name)
+        .map(|(fourcc, _)| **fourcc)
+}
+
+/// Returns the av-data codec name matching a WAVEFORMATEX format tag.
+pub fn format_tag_codec_name(format_tag: u16) -> Option<&'static str> {
+    FORMAT_TAGS
+        .iter()
+        .find(|(tag, _)| *tag == format_tag)
+        .map(|(_, name)| *name)
+}
+
+/// Returns the WAVEFORMATEX format tag matching an av-data codec name.
+pub fn codec_format_tag(name: &str) -> Option<u16> {
+    FORMAT_TAGS
+        .iter()
+        .find(|(_, n)| *n == name)
+        .map(|(tag, _)| *tag)
+}
+
+/// BITMAPINFOHEADER, the CodecPrivate of the V_MS/VFW/FOURCC tracks.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct BitmapInfoHeader {
+    pub width: i32,
+    /// Negative for top-down pictures
+    pub height: i32,
+    pub planes: u16,
+    pub bit_count: u16,
+    /// FourCC of the codec
+    pub compression: [u8; 4],
+    pub size_image: u32,
+    pub x_pels_per_meter: i32,
+    pub y_pels_per_meter: i32,
+    pub clr_used: u32,
+    pub clr_important: u32,
+}
+
+impl BitmapInfoHeader {
+    pub const SIZE: usize = 40;
+
+    /// Parses the header, and returns it along with the codec extradata following it.
+    pub fn parse(input: &[u8]) -> IResult<&[u8], BitmapInfoHeader> {
+        let (i, (_size, width, height, planes, bit_count, compression)) =
+            tuple((le_u32, le_i32, le_i32, le_u16, le_u16, take(4usize)))(input)?;
+        let (i, (size_image, x_pels_per_meter, y_pels_per_meter, clr_used, clr_important)) =
+            tuple((le_u32, le_i32, le_i32, le_u32, le_u32))(i)?;
+
+        Ok((
+            i,
+            BitmapInfoHeader {
+                width,
+                height,
+                planes,
+                bit_count,
+                compression: compression.try_into().unwrap(),
+                size_image,
+                x_pels_per_meter,
+                y_pels_per_meter,
+                clr_used,
+                clr_important,
+            },
+        ))
+    }
+
+    /// Serializes the header, followed by the codec extradata.
+    pub fn to_bytes(&self, extradata: &[u8]) -> Vec<u8> {
+        let mut v = Vec::with_capacity(Self::SIZE + extradata.len());
+
+        v.extend_from_slice(&((Self::SIZE + extradata.len()) as u32).to_le_bytes());
+        v.extend_from_slice(&self.width.to_le_bytes());
+        v.extend_from_slice(&self.height.to_le_bytes());
+        v.extend_from_slice(&self.planes.to_le_bytes());
+        v.extend_from_slice(&self.bit_count.to_le_bytes());
+        v.extend_from_slice(&self.compression);
+        v.extend_from_slice(&self.size_image.to_le_bytes());
+        v.extend_from_slice(&self.x_pels_per_meter.to_le_bytes());
+        v.extend_from_slice(&self.y_pels_per_meter.to_le_bytes());
+        v.extend_from_slice(&self.clr_used.to_le_bytes());
+        v.extend_from_slice(&self.clr_important.to_le_bytes());
+        v.extend_from_slice(extradata);
+
+        v
+    }
+}
+
+/// WAVEFORMATEX, the CodecPrivate of the A_MS/ACM tracks.
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct WaveFormatEx {
+    pub format_tag: u16,
+    pub channels: u16,
+    pub samples_per_sec: u32,
+    pub avg_bytes_per_sec: u32,
+    pub block_align: u16,
+    pub bits_per_sample: u16,
+    /// Speaker positions of a WAVEFORMATEXTENSIBLE, 0 for a plain WAVEFORMATEX
+    pub channel_mask: u32,
+}
+
+impl WaveFormatEx {
+    pub const SIZE: usize = 18;
+    const EXTENSIBLE: u16 = 0xFFFE;
+    // The SubFormat GUID following its leading format tag
+    const SUBFORMAT_GUID: [u8; 14] = [
+        0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+    ];
+
+    /// Parses the structure, and returns it along with the codec extradata following it.
+    ///
+    /// The format tag of a WAVEFORMATEXTENSIBLE is taken from its SubFormat.
+    pub fn parse(input: &[u8]) -> IResult<&[u8], WaveFormatEx> {
+        let (i, (format_tag, channels, samples_per_sec, avg_bytes_per_sec)) =
+            tuple((le_u16, le_u16, le_u32, le_u32))(input)?;
+        let (i, (block_align, bits_per_sample)) = tuple((le_u16, le_u16))(i)?;
+
+        // The size is missing from the older PCMWAVEFORMAT
+        let extradata = match le_u16::<_, nom::error::Error<_>>(i) {
+            Ok((i, size)) => &i[..usize::from(size).min(i.len())],
+            Err(_) => i,
+        };
+
+        let wf = WaveFormatEx {
+            format_tag,
+            channels,
+            samples_per_sec,
+            avg_bytes_per_sec,
+            block_align,
+            bits_per_sample,
+            channel_mask: 0,
+        };
+
+        // The SubFormat GUID follows the valid bits per sample and the channel mask
+        if format_tag == Self::EXTENSIBLE && extradata.len() >= 22 {
+            let channel_mask =
+                u32::from_le_bytes([extradata[2], extradata[3], extradata[4], extradata[5]]);
+            let format_tag = u16::from_le_bytes([extradata[6], extradata[7]]);
+            let wf = WaveFormatEx {
+                format_tag,
+                channel_mask,
+                ..wf
+            };
+            return Ok((&extradata[22..], wf));
+        }
+
+        Ok((extradata, wf))
+    }
+
+    /// Serializes the structure, followed by the codec extradata.
+    ///
+    /// A WAVEFORMATEXTENSIBLE is written if there is a channel mask.
+    pub fn to_bytes(&self, extradata: &[u8]) -> Vec<u8> {
+        let extensible = self.channel_mask != 0;
+        let ext_size = if extensible { 22 } else { 0 };
+        let mut v = Vec::with_capacity(Self::SIZE + ext_size + extradata.len());
+
+        let format_tag = if extensible {
+            Self::EXTENSIBLE
+        } else {
+            self.format_tag
+        };
+        v.extend_from_slice(&format_tag.to_le_bytes());
+        v.extend_from_slice(&self.channels.to_le_bytes());
+        v.extend_from_slice(&self.samples_per_sec.to_le_bytes());
+        v.extend_from_slice(&self.avg_bytes_per_sec.to_le_bytes());
+        v.extend_from_slice(&self.block_align.to_le_bytes());
+        v.extend_from_slice(&self.bits_per_sample.to_le_bytes());
+        v.extend_from_slice(&((ext_size + extradata.len()) as u16).to_le_bytes());
+        if extensible {
+            v.extend_from_slice(&self.bits_per_sample.to_le_bytes());
+            v.extend_from_slice(&self.channel_mask.to_le_bytes());
+            v.extend_from_slice(&self.format_tag.to_le_bytes());
+            v.extend_from_slice(&Self::SUBFORMAT_GUID);
+        }
+        v.extend_from_slice(extradata);
+
+        v
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn round_trip() {
+        for (id, name) in CODEC_IDS {
+            assert_eq!(codec_name(id), Some(*name));
+            assert_eq!(codec_name(codec_id(name).unwrap()), Some(*name));
+        }
+
+        assert_eq!(codec_name("A_AAC/MPEG4/LC/SBR"), Some("aac"));
+        assert_eq!(codec_id("aac"), Some("A_AAC"));
+        assert_eq!(codec_name("V_UNKNOWN"), None);
+        assert_eq!(codec_id("unknown"), None);
+    }
+
+    #[test]
+    fn fourcc() {
+        assert_eq!(fourcc_codec_name(b"XVID"), Some("mpeg4"));
+        assert_eq!(fourcc_codec_name(b"xvid"), Some("mpeg4"));
+        assert_eq!(fourcc_codec_name(b"H264"), Some("h264"));
+        assert_eq!(fourcc_codec_name(b"\0\0\0\0"), None);
+    }
+
+    #[test]
+    fn bitmap_info_header() {
+        let header = BitmapInfoHeader {
+            width: 320,
+            height: -240,
+            planes: 1,
+            bit_count: 24,
+            compression: *b"XVID",
+            ..Default::default()
+        };
+
+        let bytes = header.to_bytes(&[0xAA, 0xBB]);
+        assert_eq!(bytes.len(), BitmapInfoHeader::SIZE + 2);
+        assert_eq!(&bytes[..4], &[42, 0, 0, 0]);
+        assert_eq!(
+            BitmapInfoHeader::parse(&bytes),
+            Ok((&[0xAA, 0xBB][..], header))
+        );
+    }
+
+    #[test]
+    fn wave_format() {
+        let wf = WaveFormatEx {
+            format_tag: 0x0161,
+            channels: 2,
+            samples_per_sec: 44100,
+            avg_bytes_per_sec: 16000,
+            block_align: 2973,
+            bits_per_sample: 16,
+            channel_mask: 0,
+        };
+
+        let bytes = wf.to_bytes(&[0xAA, 0xBB]);
+        assert_eq!(bytes.len(), WaveFormatEx::SIZE + 2);
+        assert_eq!(
+            WaveFormatEx::parse(&bytes),
+            Ok((&[0xAA, 0xBB][..], wf.clone()))
+        );
+
+        // PCMWAVEFORMAT, without the size
+        assert_eq!(WaveFormatEx::parse(&bytes[..16]), Ok((&[][..], wf)));
+
+        // WAVEFORMATEXTENSIBLE, with the PCM SubFormat
+        #[rustfmt::skip]
+        let extensible = [
+            0xFE, 0xFF, 0x06, 0x00, 0x80, 0xBB, 0x00, 0x00, 0x00, 0x65, 0x04, 0x00,
+            0x0C, 0x00, 0x10, 0x00, 0x16, 0x00,
+            0x10, 0x00, 0x3F, 0x00, 0x00, 0x00,
+            0x01, 0x00, 0x00, 0x00, 0x00, 0x00, 0x10, 0x00,
+            0x80, 0x00, 0x00, 0xAA, 0x00, 0x38, 0x9B, 0x71,
+        ];
+        let (extradata, wf) = WaveFormatEx::parse(&extensible).unwrap();
+        assert!(extradata.is_empty());
+        assert_eq!(wf.format_tag, 0x0001);
+        assert_eq!(wf.channels, 6);
+        assert_eq!(wf.samples_per_sec, 48000);
+        assert_eq!(wf.channel_mask, 0x3F);
+        assert_eq!(wf.to_bytes(&[]), extensible);
+    }
+}
@@ -1,10 +1,32 @@
-use std::{collections::VecDeque, io::SeekFrom};
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+    io::{self, Read, SeekFrom},
+    iter,
+    sync::Arc,
+};
+
+use aes::Aes128;
+use ctr::{
+    cipher::{KeyIvInit, StreamCipher},
+    Ctr64BE,
+};
+use flate2::read::ZlibDecoder;
 
-use log::{debug, error, trace};
+use log::{debug, error, trace, warn};
 use nom::{self, Err, IResult, Needed, Offset};
 
 use av_data::{
-    audiosample::ChannelMap, packet::Packet, params::*, rational::Rational64, timeinfo::TimeInfo,
+    audiosample::Soniton,
+    packet::Packet,
+    params::*,
+    pixel::{
+        ChromaLocation, Chromaton, ColorModel, ColorPrimaries, Formaton, FromPrimitive,
+        MatrixCoefficients, TransferCharacteristic, TrichromaticEncodingSystem, YUVRange,
+        YUVSystem,
+    },
+    rational::Rational64,
+    timeinfo::TimeInfo,
 };
 use av_format::{
     buffer::Buffered,
@@ -15,11 +37,21 @@ use av_format::{
 };
 
 use crate::{
-    ebml::{self, ebml_err, ebml_header, EbmlHeader, ErrorKind},
+    codec::{
+        self,
+        channels::{default_channel_map, mask_channel_map},
+        config::CodecConfig,
+        BitmapInfoHeader, WaveFormatEx,
+    },
+    ebml::{self, check_id, ebml_element, ebml_err, ebml_header, elem_size, master_size},
+    ebml::{vid, EbmlHeader, ErrorKind},
     elements::{
-        segment, segment_element, simple_block, Audio, Cluster, Info, SeekHead, SegmentElement,
+        block, laced_frames, segment, segment_element, segment_element_eof, simple_block,
+        AttachedFile, Attachments, Audio, Chapters, Cluster, ClusterBlock, Colour,
+        ContentEncodings, ContentEncryption, Cues, Info, Lacing, SeekHead, SegmentElement,
         TrackEntry, TrackType, Tracks, Video,
     },
+    language::same_language,
 };
 
 #[derive(Debug, Clone, Default)]
@@ -28,14 +60,162 @@ pub struct MkvDemuxer {
     pub seek_head: Option<SeekHead>,
     pub info: Option<Info>,
     pub tracks: Option<Tracks>,
+    pub chapters: Option<Chapters>,
+    pub attachments: Option<Attachments>,
+    pub cues: Option<Cues>,
+    /// Position of the Segment data, which SeekHead and Cues positions are relative to
+    pub segment_offset: u64,
     pub queue: VecDeque<Event>,
     pub blockstream: Vec<u8>,
     pub params: Option<DemuxerParams>,
+    /// Stream whose packets are dropped until its next keyframe after a seek
+    pub seek_keyframe: Option<usize>,
+    /// Provides the keys to decrypt the encrypted tracks
+    pub key_provider: Option<KeyProvider>,
+}
+
+/// Callback returning the AES-128 key matching a ContentEncKeyID, if known.
+#[derive(Clone)]
+pub struct KeyProvider(Arc<KeyFn>);
+
+type KeyFn = dyn Fn(&[u8]) -> Option<[u8; 16]> + Send + Sync;
+
+impl KeyProvider {
+    pub fn new<F>(f: F) -> Self
+    where
+        F: Fn(&[u8]) -> Option<[u8; 16]> + Send + Sync + 'static,
+    {
+        KeyProvider(Arc::new(f))
+    }
+
+    pub fn key(&self, key_id: &[u8]) -> Option<[u8; 16]> {
+        (self.0)(key_id)
+    }
+}
+
+impl fmt::Debug for KeyProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyProvider")
+    }
+}
+
+/// Encryption parameters of a WebM encrypted frame, attached to its packet
+/// as [TimeInfo::user_private] when no key is available to decrypt it.
+///
+/// The packet data is then the encrypted payload, without the signal byte,
+/// the IV and the partition offsets.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EncryptionInfo {
+    pub key_id: Vec<u8>,
+    /// Initialization vector, the upper 64 bits of the AES-CTR counter block
+    pub iv: [u8; 8],
+    /// Offsets alternating between clear and encrypted partitions of the
+    /// payload, starting with a clear one. Empty if it is fully encrypted.
+    pub partitions: Vec<u32>,
+}
+
+/// Flags controlling the position picked by [MkvDemuxer::seek].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SeekFlags {
+    /// Resume from a keyframe of the requested stream.
+    pub keyframe: bool,
+    /// Resume at or before the requested timestamp, instead of at or after it.
+    pub backward: bool,
 }
 
+/// Selects the tracks to demux, the Blocks of the other tracks are skipped.
 #[derive(Debug, Clone, Default)]
 pub struct DemuxerParams {
     pub track_numbers: Option<Vec<u64>>,
+    /// Keeps the tracks matching any of the selectors, all of them if empty.
+    pub selectors: Vec<TrackSelector>,
+}
+
+/// Matches the tracks satisfying all of its criteria.
+///
+/// e.g. "the default audio in English plus the first video" takes two
+/// selectors, one with the Audio type, the `en` language and the default
+/// flag, and one with the Video type and a limit of 1.
+#[derive(Debug, Clone, Default)]
+pub struct TrackSelector {
+    pub track_type: Option<TrackType>,
+    /// Language, matched against the LanguageIETF and its primary language
+    /// subtag, or the ISO 639-2 Language of the tracks without one. ISO 639-1
+    /// and ISO 639-2 codes naming the same language match each other.
+    pub language: Option<String>,
+    /// Matroska codec ID or av-data codec name
+    pub codec_id: Option<String>,
+    pub default: Option<bool>,
+    pub forced: Option<bool>,
+    /// Keeps at most this many tracks of each type, in the Tracks order
+    pub limit: Option<usize>,
+}
+
+impl TrackSelector {
+    pub fn matches(&self, t: &TrackEntry) -> bool {
+        self.track_type
+            .is_none_or(|track_type| track_type == TrackType::from(t.track_type))
+            && self
+                .language
+                .as_deref()
+                .is_none_or(|lang| track_language_matches(t, lang))
+            && self.codec_id.as_deref().is_none_or(|id| {
+                t.codec_id == id || track_entry_codec_id(t).is_some_and(|name| name == id)
+            })
+            && self
+                .default
+                .is_none_or(|default| default == (t.flag_default != 0))
+            && self
+                .forced
+                .is_none_or(|forced| forced == (t.flag_forced != 0))
+    }
+}
+
+// The LanguageIETF, when present, takes precedence over the Language
+fn track_language_matches(t: &TrackEntry, lang: &str) -> bool {
+    match t.language_ietf.as_deref() {
+        Some(ietf) => {
+            ietf.eq_ignore_ascii_case(lang)
+                || ietf
+                    .split('-')
+                    .next()
+                    .is_some_and(|primary| same_language(primary, lang))
+        }
+        None => same_language(&t.language, lang),
+    }
+}
+
+impl DemuxerParams {
+    /// Keeps only the selected tracks.
+    pub fn select(&self, tracks: &mut Vec<TrackEntry>) {
+        if let Some(track_numbers) = &self.track_numbers {
+            tracks.retain(|tr| track_numbers.contains(&tr.track_number));
+        }
+
+        if self.selectors.is_empty() {
+            return;
+        }
+
+        // Tracks matched so far by each selector, per track type
+        let mut matched: HashMap<(usize, u64), usize> = HashMap::new();
+
+        tracks.retain(|tr| {
+            let mut keep = false;
+
+            for (n, selector) in self.selectors.iter().enumerate() {
+                if !selector.matches(tr) {
+                    continue;
+                }
+                let count = matched.entry((n, tr.track_type)).or_default();
+                if selector.limit.is_none_or(|limit| *count < limit) {
+                    *count += 1;
+                    keep = true;
+                }
+            }
+
+            keep
+        });
+    }
 }
 
 impl MkvDemuxer {
@@ -45,9 +225,15 @@ impl MkvDemuxer {
             seek_head: None,
             info: None,
             tracks: None,
+            chapters: None,
+            attachments: None,
+            cues: None,
+            segment_offset: 0,
             queue: VecDeque::new(),
             blockstream: Vec::new(),
             params: None,
+            seek_keyframe: None,
+            key_provider: None,
         }
     }
 
@@ -68,9 +254,13 @@ impl MkvDemuxer {
 
         let (mut input, _) = segment(i1)?;
 
+        self.segment_offset = original_input.offset(input) as u64;
         self.seek_head = None;
         self.info = None;
         self.tracks = None;
+        self.chapters = None;
+        self.attachments = None;
+        self.cues = None;
 
         loop {
             if self.seek_head.is_some() && self.info.is_some() && self.tracks.is_some() {
@@ -103,10 +293,7 @@ impl MkvDemuxer {
 
                         // Only keep tracks we're interested in
                         if let Some(params) = &self.params {
-                            if let Some(track_numbers) = &params.track_numbers {
-                                t.tracks
-                                    .retain(|tr| track_numbers.contains(&tr.track_number));
-                            }
+                            params.select(&mut t.tracks);
                         };
 
                         Some(t)
@@ -114,6 +301,30 @@ impl MkvDemuxer {
                         return ebml_err(0x1654AE6B, ErrorKind::DuplicateSegment);
                     }
                 }
+                SegmentElement::Chapters(c) => {
+                    trace!("got chapters: {c:#?}");
+                    self.chapters = if self.chapters.is_none() {
+                        Some(c)
+                    } else {
+                        return ebml_err(0x1043A770, ErrorKind::DuplicateSegment);
+                    };
+                }
+                SegmentElement::Attachments(a) => {
+                    trace!("got {} attachments", a.files.len());
+                    self.attachments = if self.attachments.is_none() {
+                        Some(a)
+                    } else {
+                        return ebml_err(0x1941A469, ErrorKind::DuplicateSegment);
+                    };
+                }
+                SegmentElement::Cues(c) => {
+                    trace!("got {} cue points", c.cue_points.len());
+                    self.cues = if self.cues.is_none() {
+                        Some(c)
+                    } else {
+                        return ebml_err(0x1C53BB6B, ErrorKind::DuplicateSegment);
+                    };
+                }
                 el => {
                     debug!("got element: {el:#?}");
                 }
@@ -122,6 +333,210 @@ impl MkvDemuxer {
             input = i3;
         }
     }
+
+    /// Lists the files attached to the Segment, if any were found.
+    pub fn attachments(&self) -> &[AttachedFile] {
+        self.attachments
+            .as_ref()
+            .map_or(&[], |a| a.files.as_slice())
+    }
+
+    /// Returns the data of the attached file with the given FileUID.
+    pub fn attachment_data(&self, uid: u64) -> Option<&[u8]> {
+        self.attachments
+            .as_ref()
+            .and_then(|a| a.lookup(uid))
+            .map(|f| f.data.as_slice())
+    }
+
+    /// Returns the attached file a track refers to through its AttachmentLink.
+    pub fn track_attachment(&self, track: &TrackEntry) -> Option<&AttachedFile> {
+        let uid = track.attachment_link?;
+        self.attachments.as_ref().and_then(|a| a.lookup(uid))
+    }
+
+    /// Returns the absolute position of the Cues, as referenced by the SeekHead.
+    pub fn cues_position(&self) -> Option<u64> {
+        self.seek_head
+            .as_ref()?
+            .positions
+            .iter()
+            .find(|s| s.id == [0x1C, 0x53, 0xBB, 0x6B])
+            .map(|s| self.segment_offset + s.position)
+    }
+
+    /// Seeks the stream with index `stream_index` to `timestamp`, expressed in
+    /// the stream timebase.
+    ///
+    /// The Cues are used when available, otherwise the position is found by
+    /// bisecting the Cluster timestamps. On success `buf` is positioned at the
+    /// returned byte offset, where [Demuxer::read_event] resumes demuxing.
+    pub fn seek(
+        &mut self,
+        buf: &mut dyn Buffered,
+        stream_index: usize,
+        timestamp: i64,
+        flags: SeekFlags,
+    ) -> Result<u64> {
+        let track = self
+            .tracks
+            .as_ref()
+            .and_then(|t| {
+                t.tracks
+                    .iter()
+                    .find(|tr| tr.stream_index as usize == stream_index)
+            })
+            .ok_or(Error::InvalidData)?;
+        let track_number = track.track_number;
+
+        // Cue and Cluster timestamps are expressed in Segment ticks
+        let target = (timestamp.max(0) as f64 * track.track_timestamp_scale).round() as u64;
+
+        if self.cues.is_none() {
+            if let Some(pos) = self.cues_position() {
+                match read_element(buf, pos) {
+                    Ok(data) => match segment_element(&data) {
+                        Ok((_, SegmentElement::Cues(c))) => self.cues = Some(c),
+                        e => warn!("cannot parse the Cues at {pos}: {e:?}"),
+                    },
+                    Err(e) => warn!("cannot read the Cues at {pos}: {e:?}"),
+                }
+            }
+        }
+
+        let cue = self
+            .cues
+            .as_ref()
+            .and_then(|c| cue_position(c, track_number, target, flags.backward));
+
+        let position = match cue {
+            Some((time, pos)) if flags.backward || time >= target => self.segment_offset + pos,
+            // Past the last CuePoint, only the Clusters following it are searched
+            Some((_, pos)) => {
+                self.bisect_clusters(buf, self.segment_offset + pos, target, false)?
+            }
+            None => {
+                let position =
+                    self.bisect_clusters(buf, self.segment_offset, target, flags.backward)?;
+                if flags.backward && flags.keyframe {
+                    self.keyframe_cluster(buf, position, track_number, timestamp)?
+                } else {
+                    position
+                }
+            }
+        };
+
+        debug!("seeking stream {stream_index} to {timestamp}, resuming at {position}");
+
+        self.queue.clear();
+        self.seek_keyframe = flags.keyframe.then_some(stream_index);
+        buf.seek(SeekFrom::Start(position))?;
+
+        Ok(position)
+    }
+
+    // Finds the last Cluster starting at or before `target`, or the first one
+    // starting at or after it if `backward` is not set, from the position `from`.
+    fn bisect_clusters(
+        &self,
+        buf: &mut dyn Buffered,
+        from: u64,
+        target: u64,
+        backward: bool,
+    ) -> Result<u64> {
+        let end = buf.seek(SeekFrom::End(0))?;
+        let first = next_cluster(buf, from, end)?.ok_or(Error::InvalidData)?;
+
+        if first.1 >= target {
+            return Ok(first.0);
+        }
+
+        let mut best = first;
+        let (mut lo, mut hi) = (first.0 + 1, end);
+        while lo < hi {
+            let mid = lo + (hi - lo) / 2;
+            match next_cluster(buf, mid, hi)? {
+                Some((pos, ts)) if ts <= target => {
+                    best = (pos, ts);
+                    lo = pos + 1;
+                }
+                _ => hi = mid,
+            }
+        }
+
+        if backward || best.1 == target {
+            Ok(best.0)
+        } else {
+            next_cluster(buf, best.0 + 1, end)?
+                .map(|(pos, _)| pos)
+                .ok_or(Error::InvalidData)
+        }
+    }
+
+    // Steps back from the Cluster at `position` to the last one holding a
+    // keyframe of the track at or before `timestamp`, in Track ticks, so that
+    // skip_to_keyframe does not resume after it.
+    fn keyframe_cluster(
+        &self,
+        buf: &mut dyn Buffered,
+        mut position: u64,
+        track_number: u64,
+        timestamp: i64,
+    ) -> Result<u64> {
+        let Some(track) = self.tracks.as_ref().and_then(|t| t.find(track_number)) else {
+            return Ok(position);
+        };
+
+        loop {
+            let data = match read_element(buf, position) {
+                Ok(data) => data,
+                Err(e) => {
+                    warn!("cannot read the Cluster at {position}: {e:?}");
+                    return Ok(position);
+                }
+            };
+            match segment_element(&data) {
+                Ok((_, SegmentElement::Cluster(c))) if c.has_keyframe(track, timestamp) => {
+                    return Ok(position)
+                }
+                Ok((_, SegmentElement::Cluster(_))) => {}
+                e => {
+                    warn!("cannot parse the Cluster at {position}: {e:?}");
+                    return Ok(position);
+                }
+            }
+
+            match prev_cluster(buf, self.segment_offset, position)? {
+                Some(prev) => position = prev,
+                // Nothing is before the first Cluster
+                None => return Ok(position),
+            }
+        }
+    }
+
+    // Drops the packets of the stream being seeked until its first keyframe
+    fn skip_to_keyframe(&mut self, packets: &mut Vec<Event>) {
+        let Some(index) = self.seek_keyframe else {
+            return;
+        };
+
+        let is_stream =
+            |e: &Event| matches!(e, Event::NewPacket(p) if p.stream_index == index as isize);
+        let keyframe = packets
+            .iter()
+            .position(|e| is_stream(e) && matches!(e, Event::NewPacket(p) if p.is_key));
+
+        let end = keyframe.unwrap_or(packets.len());
+        let mut n = 0;
+        packets.retain(|e| {
+            n += 1;
+            n > end || !is_stream(e)
+        });
+
+        if keyframe.is_some() {
+            self.seek_keyframe = None;
+        }
+    }
 }
 
 impl Demuxer for MkvDemuxer {
@@ -159,16 +574,54 @@ impl Demuxer for MkvDemuxer {
         if let Some(event) = self.queue.pop_front() {
             Ok((SeekFrom::Current(0), event))
         } else {
-            match segment_element(buf.data()) {
+            let res = match segment_element(buf.data()) {
+                // An unknown-sized Element only ends with the next one, or with the stream
+                Err(Err::Incomplete(_)) if has_unknown_size(buf.data()) => {
+                    if !end_of_stream(buf)? {
+                        return Ok((SeekFrom::Current(0), Event::MoreDataNeeded(0)));
+                    }
+                    segment_element_eof(buf.data())
+                }
+                res => res,
+            };
+
+            match res {
                 Ok((i, element)) => {
                     let seek = SeekFrom::Current(buf.data().offset(i) as i64);
-                    if let SegmentElement::Cluster(c) = element {
-                        debug!("got cluster element at timestamp: {}", c.timestamp);
-                        let mut packets = c.generate_packets(self.tracks.as_ref().unwrap());
-                        self.queue.extend(packets.drain(..));
-                        if let Some(event) = self.queue.pop_front() {
-                            return Ok((seek, event));
+                    match element {
+                        SegmentElement::Cluster(c) => {
+                            debug!("got cluster element at timestamp: {}", c.timestamp);
+                            let mut packets = c.generate_packets(
+                                self.info.as_ref().unwrap(),
+                                self.tracks.as_ref().unwrap(),
+                                self.key_provider.as_ref(),
+                            );
+                            self.skip_to_keyframe(&mut packets);
+                            self.queue.extend(packets.drain(..));
+                            if let Some(event) = self.queue.pop_front() {
+                                return Ok((seek, event));
+                            }
                         }
+                        // Chapters and Attachments may be stored after the Tracks
+                        SegmentElement::Chapters(c) => {
+                            trace!("got chapters: {c:#?}");
+                            if self.chapters.is_none() {
+                                self.chapters = Some(c);
+                            }
+                        }
+                        SegmentElement::Attachments(a) => {
+                            trace!("got {} attachments", a.files.len());
+                            if self.attachments.is_none() {
+                                self.attachments = Some(a);
+                            }
+                        }
+                        SegmentElement::Cues(c) => {
+                            trace!("got {} cue points", c.cue_points.len());
+                            if self.cues.is_none() {
+                                self.cues = Some(c);
+                            }
+                        }
+                        _ => {}
                     }
                     Ok((seek, Event::MoreDataNeeded(0)))
                 }
@@ -187,101 +640,796 @@ impl Demuxer for MkvDemuxer {
 }
 
 fn track_entry_codec_id(t: &TrackEntry) -> Option<String> {
-    // TODO: Support V_QUICKTIME and V_MS/VFW/FOURCC
-    match t.codec_id.as_ref() {
-        "A_OPUS" => Some("opus".to_owned()),
-        "A_VORBIS" => Some("vorbis".to_owned()),
-        "V_AV1" => Some("av1".to_owned()),
-        "V_VP8" => Some("vp8".to_owned()),
-        "V_VP9" => Some("vp9".to_owned()),
-        _ => None,
-    }
+    let private = t.codec_private.as_deref().unwrap_or_default();
+
+    let name = match t.codec_id.as_ref() {
+        // The FourCC follows the size of the ImageDescription
+        "V_QUICKTIME" => private.get(4..8).and_then(codec::fourcc_codec_name),
+        id => codec::codec_name(id),
+    };
+
+    name.map(String::from)
 }
 
 fn track_entry_video_kind(video: &Video) -> Option<MediaKind> {
     let v = VideoInfo {
         width: video.pixel_width as usize,
         height: video.pixel_height as usize,
-        // TODO parse Colour and/or CodecPrivate to extract the format
-        format: None,
+        format: video.colour.as_ref().map(|c| Arc::new(colour_format(c))),
     };
     Some(MediaKind::Video(v))
 }
 
-fn track_entry_audio_kind(audio: &Audio) -> Option<MediaKind> {
+// Builds a planar pixel format out of the Colour element
+//
+// Missing fields are assumed to describe 8-bit 4:2:0, the most common layout.
+fn colour_format(colour: &Colour) -> Formaton {
+    let range = match colour.range {
+        2 => YUVRange::Full,
+        _ => YUVRange::Limited,
+    };
+    let model = match colour.matrix_coefficients {
+        0 => TrichromaticEncodingSystem::RGB,
+        8 => TrichromaticEncodingSystem::YUV(YUVSystem::YCoCg),
+        14 => TrichromaticEncodingSystem::YUV(YUVSystem::ICtCp),
+        _ => TrichromaticEncodingSystem::YUV(YUVSystem::YCbCr(range)),
+    };
+
+    let depth = match colour.bits_per_channel {
+        0 => 8,
+        bits => bits.min(u8::MAX as u64) as u8,
+    };
+    let (h_ss, v_ss) = match model {
+        TrichromaticEncodingSystem::RGB => (0, 0),
+        _ => (
+            subsampling_shift(colour.chroma_subsampling_horz),
+            subsampling_shift(colour.chroma_subsampling_vert),
+        ),
+    };
+    let components = [
+        Chromaton::yuvhb(0, 0, depth, 0),
+        Chromaton::yuvhb(h_ss, v_ss, depth, 1),
+        Chromaton::yuvhb(h_ss, v_ss, depth, 2),
+    ];
+
+    let mut format = Formaton::new(
+        ColorModel::Trichromatic(model),
+        &components,
+        0,
+        false,
+        false,
+        false,
+    );
+    format.matrix = MatrixCoefficients::from_u64(colour.matrix_coefficients)
+        .unwrap_or(MatrixCoefficients::Unspecified);
+    format.xfer = TransferCharacteristic::from_u64(colour.transfer_characteristics)
+        .unwrap_or(TransferCharacteristic::Unspecified);
+    format.primaries =
+        ColorPrimaries::from_u64(colour.primaries).unwrap_or(ColorPrimaries::Unspecified);
+    // Siting is 1 for collocated and 2 for half a sample away
+    format.chroma_location = match (colour.chroma_siting_horz, colour.chroma_siting_vert) {
+        (1, 1) => ChromaLocation::TopLeft,
+        (1, _) => ChromaLocation::Left,
+        (2, 1) => ChromaLocation::Top,
+        (2, _) => ChromaLocation::Center,
+        _ => ChromaLocation::Unspecified,
+    };
+
+    format
+}
+
+// ChromaSubsampling counts the chroma samples removed per sample kept,
+// while a Chromaton takes its log2
+fn subsampling_shift(subsampling: Option<u64>) -> u8 {
+    match subsampling.and_then(|n| n.checked_add(1)) {
+        Some(n) if n.is_power_of_two() => n.trailing_zeros() as u8,
+        Some(n) => {
+            warn!("unsupported ChromaSubsampling {}, assuming 4:2:0", n - 1);
+            1
+        }
+        // Missing values are assumed to be 4:2:0, the most common layout
+        None => 1,
+    }
+}
+
+fn track_entry_audio_kind(audio: &Audio, codec: Option<&str>) -> Option<MediaKind> {
     let rate = audio
         .output_sampling_frequency
         .unwrap_or(audio.sampling_frequency);
     let a = AudioInfo {
-        rate: rate as usize,
-        map: Some(ChannelMap::default_map(audio.channels as usize)),
-        format: None,
+        rate: rate.round() as usize,
+        map: default_channel_map(audio.channels as usize),
+        format: audio
+            .bit_depth
+            .map(|bits| Arc::new(sample_format(codec, bits as u8))),
     };
     Some(MediaKind::Audio(a))
 }
 
-fn track_entry_media_kind(t: &TrackEntry) -> Option<MediaKind> {
+// PCM is described as stored, the other codecs by their decoded samples
+fn sample_format(codec: Option<&str>, bits: u8) -> Soniton {
+    match codec {
+        // 8 bits PCM is unsigned
+        Some("pcm_int_le") => Soniton::new(bits, false, false, false, false, bits > 8),
+        Some("pcm_int_be") => Soniton::new(bits, true, false, false, false, bits > 8),
+        Some("pcm_float") => Soniton::new(bits, false, false, false, true, true),
+        _ => Soniton::new(bits, false, false, false, false, true),
+    }
+}
+
+fn track_entry_media_kind(t: &TrackEntry, codec: Option<&str>) -> Option<MediaKind> {
     match t.track_type.into() {
         TrackType::Video => t.video.as_ref().and_then(track_entry_video_kind),
-        TrackType::Audio => t.audio.as_ref().and_then(track_entry_audio_kind),
-        TrackType::Other => None,
+        TrackType::Audio => t
+            .audio
+            .as_ref()
+            .and_then(|a| track_entry_audio_kind(a, codec)),
+        // Subtitles and the other track types have no av-data kind,
+        // the codec identifies them
+        _ => None,
+    }
+}
+
+fn has_unknown_size(input: &[u8]) -> bool {
+    matches!(
+        nom::sequence::pair(vid, master_size)(input),
+        Ok((_, (_, None)))
+    )
+}
+
+// Fills the buffer, growing it if it is full, and tells whether no more data is available
+fn end_of_stream(buf: &mut dyn Buffered) -> Result<bool> {
+    let len = buf.data().len();
+
+    if buf.fill_buf()?.len() > len {
+        return Ok(false);
+    }
+
+    buf.grow(len.max(4096));
+    Ok(buf.fill_buf()?.len() == len)
+}
+
+// Picks the best CuePoint for `target`, and returns its time and its Cluster
+// position, relative to the Segment. Going forward past the last CuePoint, the
+// last one is returned.
+fn cue_position(cues: &Cues, track_number: u64, target: u64, backward: bool) -> Option<(u64, u64)> {
+    let track_points = |track: Option<u64>| -> Vec<(u64, u64)> {
+        cues.cue_points
+            .iter()
+            .filter_map(|cp| {
+                cp.track_positions
+                    .iter()
+                    .find(|tp| track.is_none_or(|t| tp.track == t))
+                    .map(|tp| (cp.time, tp.cluster_position))
+            })
+            .collect()
+    };
+
+    // Fall back to the CuePoints of other tracks if this one is not indexed
+    let mut points = track_points(Some(track_number));
+    if points.is_empty() {
+        points = track_points(None);
+    }
+
+    if backward {
+        points
+            .iter()
+            .rev()
+            .find(|(time, _)| *time <= target)
+            .or(points.first())
+            .copied()
+    } else {
+        points
+            .iter()
+            .find(|(time, _)| *time >= target)
+            .or(points.last())
+            .copied()
     }
 }
 
+fn read_at(buf: &mut dyn Buffered, pos: u64, len: usize) -> Result<Vec<u8>> {
+    buf.seek(SeekFrom::Start(pos))?;
+
+    let mut data = Vec::with_capacity(len);
+    buf.take(len as u64).read_to_end(&mut data)?;
+
+    Ok(data)
+}
+
+// Reads the whole element starting at `pos`
+fn read_element(buf: &mut dyn Buffered, pos: u64) -> Result<Vec<u8>> {
+    let header = read_at(buf, pos, 12)?;
+    let (i, size) = match nom::sequence::preceded(vid, elem_size)(&header) {
+        Ok(res) => res,
+        Err(_) => return Err(Error::InvalidData),
+    };
+
+    read_at(buf, pos, header.offset(i) + size)
+}
+
+// Finds the first Cluster starting in `from..to`, and returns its position and timestamp
+fn next_cluster(buf: &mut dyn Buffered, from: u64, to: u64) -> Result<Option<(u64, u64)>> {
+    const CHUNK: usize = 64 * 1024;
+    // Large enough for a Cluster header, a CRC-32 and the Cluster Timestamp
+    const OVERLAP: usize = 64;
+
+    let mut pos = from;
+    while pos < to {
+        let data = read_at(buf, pos, CHUNK)?;
+
+        for idx in 0..data.len().saturating_sub(3) {
+            if data[idx..idx + 4] != [0x1F, 0x43, 0xB6, 0x75] {
+                continue;
+            }
+
+            if pos + idx as u64 >= to {
+                return Ok(None);
+            }
+
+            // The children before the Timestamp may go past the chunk
+            let ts = match cluster_timestamp(&data[idx..]) {
+                None if idx > 0 => cluster_timestamp(&read_at(buf, pos + idx as u64, CHUNK)?),
+                ts => ts,
+            };
+            if let Some(ts) = ts {
+                return Ok(Some((pos + idx as u64, ts)));
+            }
+        }
+
+        if data.len() < CHUNK {
+            break;
+        }
+
+        pos += (CHUNK - OVERLAP) as u64;
+    }
+
+    Ok(None)
+}
+
+// Finds the last Cluster starting in `from..to`
+fn prev_cluster(buf: &mut dyn Buffered, from: u64, to: u64) -> Result<Option<u64>> {
+    let mut best = None;
+    let (mut lo, mut hi) = (from, to);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        match next_cluster(buf, mid, to)? {
+            Some((pos, _)) => {
+                best = Some(pos);
+                lo = pos + 1;
+            }
+            None => hi = mid,
+        }
+    }
+
+    Ok(best)
+}
+
+// Reads the Timestamp of the Cluster starting `input`, which may follow any
+// other child of the Cluster
+fn cluster_timestamp(input: &[u8]) -> Option<u64> {
+    let (mut i, _) = nom::sequence::pair(check_id(0x1F43B675), elem_size)(input).ok()?;
+    loop {
+        if let Ok((_, ts)) = ebml_element(0xE7)(i) {
+            return Some(ts);
+        }
+        let (rest, size) = nom::sequence::preceded(vid, elem_size)(i).ok()?;
+        i = rest.get(size..)?;
+    }
+}
+
+// Duration of a track tick, in nanoseconds
+fn track_scale(info: &Info, t: &TrackEntry) -> f64 {
+    t.track_timestamp_scale * info.timestamp_scale as f64
+}
+
+// The TrackTimestampScale is a float, the fractional nanoseconds of a tick
+// are kept down to the femtosecond
+fn track_timebase(info: &Info, t: &TrackEntry) -> Rational64 {
+    let femtoseconds = (track_scale(info, t) * 1e6).round() as i64;
+    Rational64::new(femtoseconds, 1_000_000_000_000_000)
+}
+
 pub fn track_to_stream(info: &Info, t: &TrackEntry) -> Stream {
-    let num = (t.track_timestamp_scale * info.timestamp_scale as f64) as i64;
+    // TODO: Extend CodecParams and fill it with the remaining information
+    let codec_id = track_entry_codec_id(t);
+    let mut params = CodecParams {
+        extradata: track_codec_private(t),
+        bit_rate: 0,
+        delay: 0,
+        convergence_window: t.seek_pre_roll as usize,
+        kind: track_entry_media_kind(t, codec_id.as_deref()),
+        codec_id,
+    };
+
+    // Compatibility tracks describe the actual codec in their CodecPrivate
+    match t.codec_id.as_ref() {
+        "V_MS/VFW/FOURCC" => vfw_params(&mut params),
+        "A_MS/ACM" => acm_params(&mut params),
+        _ => {}
+    }
+
+    let config = match (params.codec_id.as_deref(), params.extradata.as_deref()) {
+        (Some(codec), Some(data)) => CodecConfig::parse(codec, data),
+        _ => None,
+    };
+    if let Some(ref config) = config {
+        config_params(&mut params, config);
+    }
+
+    // The CodecDelay is in nanoseconds, the stream delay in samples
+    if let Some(MediaKind::Audio(ref a)) = params.kind {
+        if t.codec_delay > 0 {
+            params.delay = (t.codec_delay as f64 * a.rate as f64 / 1e9).round() as usize;
+        }
+    }
 
     Stream {
         id: t.track_uid as isize,
         index: t.stream_index as usize,
         start: None,
         duration: t.default_duration,
-        timebase: Rational64::new(num, 1000 * 1000 * 1000),
-        // TODO: Extend CodecParams and fill it with the remaining information
-        params: CodecParams {
-            extradata: t.codec_private.clone(),
-            bit_rate: 0,
-            delay: t.codec_delay as usize,
-            convergence_window: t.seek_pre_roll as usize,
-            codec_id: track_entry_codec_id(t),
-            kind: track_entry_media_kind(t),
-        },
-        user_private: None,
+        timebase: track_timebase(info, t),
+        params,
+        // The whole decoder configuration, for the codec specific information
+        user_private: config.map(|c| Arc::new(c) as _),
+    }
+}
+
+// Fills the parameters from the decoder configuration of the CodecPrivate
+fn config_params(params: &mut CodecParams, config: &CodecConfig) {
+    let (rate, bits) = match config {
+        CodecConfig::Opus(head) => {
+            params.delay = usize::from(head.pre_skip);
+            (None, None)
+        }
+        CodecConfig::Vorbis(header) => {
+            if header.bitrate_nominal > 0 {
+                params.bit_rate = header.bitrate_nominal as usize;
+            }
+            (Some(header.sample_rate), None)
+        }
+        CodecConfig::Flac(info) => (Some(info.sample_rate), Some(info.bits_per_sample)),
+        // The implicit SBR output rate is only found in the track
+        CodecConfig::Aac(asc) => (asc.extension_sample_rate, None),
+        CodecConfig::Theora(header) => {
+            if let Some(MediaKind::Video(ref mut v)) = params.kind {
+                v.width = header.picture_width as usize;
+                v.height = header.picture_height as usize;
+            }
+            return;
+        }
+        _ => return,
+    };
+
+    if let Some(MediaKind::Audio(ref mut a)) = params.kind {
+        if let Some(rate) = rate {
+            a.rate = rate as usize;
+        }
+        if let Some(map) = config.channel_map() {
+            a.map = Some(map);
+        }
+        if let Some(bits) = bits {
+            a.format = Some(Arc::new(sample_format(params.codec_id.as_deref(), bits)));
+        }
     }
 }
 
+// Fills the parameters from the BITMAPINFOHEADER of a V_MS/VFW/FOURCC track
+fn vfw_params(params: &mut CodecParams) {
+    let (header, extradata) = match params.extradata.as_deref().map(BitmapInfoHeader::parse) {
+        Some(Ok((extradata, header))) => (header, extradata.to_vec()),
+        _ => {
+            warn!("invalid BITMAPINFOHEADER");
+            return;
+        }
+    };
+
+    params.codec_id = codec::fourcc_codec_name(&header.compression).map(String::from);
+    params.extradata = (!extradata.is_empty()).then_some(extradata);
+
+    let (width, height) = (
+        header.width.unsigned_abs() as usize,
+        header.height.unsigned_abs() as usize,
+    );
+    match params.kind {
+        Some(MediaKind::Video(ref mut v)) => {
+            v.width = width;
+            v.height = height;
+        }
+        _ => {
+            params.kind = Some(MediaKind::Video(VideoInfo {
+                width,
+                height,
+                format: None,
+            }))
+        }
+    }
+}
+
+// Fills the parameters from the WAVEFORMATEX of an A_MS/ACM track
+fn acm_params(params: &mut CodecParams) {
+    let (wf, extradata) = match params.extradata.as_deref().map(WaveFormatEx::parse) {
+        Some(Ok((extradata, wf))) => (wf, extradata.to_vec()),
+        _ => {
+            warn!("invalid WAVEFORMATEX");
+            return;
+        }
+    };
+
+    params.codec_id = codec::format_tag_codec_name(wf.format_tag).map(String::from);
+    params.extradata = (!extradata.is_empty()).then_some(extradata);
+    params.bit_rate = wf.avg_bytes_per_sec as usize * 8;
+
+    let codec = params.codec_id.as_deref();
+    let format = match codec {
+        Some("pcm_int_le" | "pcm_float") => Some(sample_format(codec, wf.bits_per_sample as u8)),
+        _ => None,
+    };
+    let map = match wf.channel_mask {
+        0 => default_channel_map(wf.channels as usize),
+        mask => Some(mask_channel_map(mask)),
+    };
+
+    params.kind = Some(MediaKind::Audio(AudioInfo {
+        rate: wf.samples_per_sec as usize,
+        map,
+        format: format.map(Arc::new),
+    }));
+}
+
 impl<'a> Cluster<'a> {
-    pub fn generate_packets(&self, tracks: &Tracks) -> Vec<Event> {
+    pub fn generate_packets(
+        &self,
+        info: &Info,
+        tracks: &Tracks,
+        keys: Option<&KeyProvider>,
+    ) -> Vec<Event> {
         let mut v = Vec::new();
 
-        for block_data in self.simple_block.iter() {
-            if let Ok((i, block)) = simple_block(block_data) {
-                debug!("parsing simple block: {:?}", block);
-                if let Some(index) = tracks.lookup(block.track_number) {
-                    let packet = Packet {
-                        data: i.into(),
-                        t: TimeInfo {
-                            pts: Some(i64::from(block.timestamp)),
-                            dts: None,
-                            duration: None,
-                            timebase: None,
-                            user_private: None,
-                        },
-                        pos: None,
-                        stream_index: index as isize,
-                        is_key: block.keyframe,
-                        is_corrupted: false,
-                    };
-
-                    v.push(Event::NewPacket(packet));
+        for cluster_block in self.blocks.iter() {
+            match cluster_block {
+                ClusterBlock::SimpleBlock(block_data) => {
+                    if let Ok((i, block)) = simple_block(block_data) {
+                        debug!("parsing simple block: {:?}", block);
+                        // The Blocks of the tracks not selected are skipped
+                        if let Some(track) = tracks.find(block.track_number) {
+                            let data = BlockData {
+                                timestamp: self.block_timestamp(track, block.timestamp),
+                                lacing: &block.lacing,
+                                data: i,
+                                is_key: block.keyframe,
+                                duration: None,
+                            };
+                            v.extend(block_packets(info, track, data, keys));
+                        }
+                    } else {
+                        error!("error parsing simple block");
+                    }
+                }
+                ClusterBlock::BlockGroup(group) => {
+                    if let Ok((i, block)) = block(group.block) {
+                        debug!("parsing block group: {:?}", block);
+                        if let Some(track) = tracks.find(block.track_number) {
+                            // A Block without any ReferenceBlock only references itself
+                            let data = BlockData {
+                                timestamp: self.block_timestamp(track, block.timestamp),
+                                lacing: &block.lacing,
+                                data: i,
+                                is_key: group.reference_block.is_none(),
+                                duration: group.block_duration,
+                            };
+                            v.extend(block_packets(info, track, data, keys));
+                        }
+                    } else {
+                        error!("error parsing block group");
+                    }
                 }
-            } else {
-                error!("error parsing simple block");
             }
         }
 
         v
     }
+
+    /// Tells whether the Cluster holds a keyframe of `track` at or before
+    /// `timestamp`, expressed in Track ticks.
+    pub fn has_keyframe(&self, track: &TrackEntry, timestamp: i64) -> bool {
+        let is_keyframe = |track_number, block_timestamp| {
+            track_number == track.track_number
+                && self.block_timestamp(track, block_timestamp) <= timestamp
+        };
+
+        self.simple_blocks()
+            .filter_map(|data| simple_block(data).ok())
+            .any(|(_, b)| b.keyframe && is_keyframe(b.track_number, b.timestamp))
+            || self
+                .block_groups()
+                .filter(|g| g.reference_block.is_none())
+                .filter_map(|g| block(g.block).ok())
+                .any(|(_, b)| is_keyframe(b.track_number, b.timestamp))
+    }
+
+    /// Computes the absolute timestamp of a Block in the timebase of its track.
+    ///
+    /// The Cluster timestamp is expressed in Segment ticks, while the relative
+    /// Block timestamp is expressed in Track ticks.
+    pub fn block_timestamp(&self, track: &TrackEntry, timestamp: i16) -> i64 {
+        (self.timestamp as f64 / track.track_timestamp_scale).round() as i64 + i64::from(timestamp)
+    }
+}
+
+// A Block, with the properties shared by all its frames
+struct BlockData<'a> {
+    // Absolute timestamp, in Track ticks
+    timestamp: i64,
+    lacing: &'a Lacing,
+    data: &'a [u8],
+    is_key: bool,
+    duration: Option<u64>,
+}
+
+// Generates one packet per frame stored in the Block data
+fn block_packets(
+    info: &Info,
+    track: &TrackEntry,
+    block: BlockData,
+    keys: Option<&KeyProvider>,
+) -> Vec<Event> {
+    let BlockData {
+        timestamp,
+        lacing,
+        data,
+        is_key,
+        duration: block_duration,
+    } = block;
+
+    let frames = match laced_frames(lacing, data) {
+        Ok((_, frames)) => frames,
+        Err(e) => {
+            error!("error parsing laced frames: {e:?}");
+            return Vec::new();
+        }
+    };
+    let timebase = track_timebase(info, track);
+
+    // Laced frames after the first one have no timestamp of their
+    // own, derive it from the track default duration if possible.
+    let default_duration = track
+        .default_duration
+        .map(|d| (d as f64 / track_scale(info, track)).round() as u64);
+
+    // The BlockDuration covers all the frames of the Block
+    let duration = match block_duration {
+        Some(d) => Some(d / frames.len() as u64),
+        None => default_duration,
+    };
+
+    frames
+        .into_iter()
+        .enumerate()
+        .map(|(n, frame)| {
+            let pts = match (n, duration) {
+                (0, _) => Some(timestamp),
+                (n, Some(d)) => Some(timestamp + (n as u64 * d) as i64),
+                (_, None) => None,
+            };
+
+            let (data, encryption, is_corrupted) = match track.content_encodings {
+                Some(ref encodings) => match decode_content(encodings, SCOPE_FRAMES, frame, keys) {
+                    Ok((data, encryption)) => (data, encryption, false),
+                    Err(e) => {
+                        error!("cannot decode frame of track {}: {e}", track.track_number);
+                        (frame.into(), None, true)
+                    }
+                },
+                None => (frame.into(), None, false),
+            };
+
+            Event::NewPacket(Packet {
+                data,
+                t: TimeInfo {
+                    pts,
+                    dts: None,
+                    duration,
+                    timebase: Some(timebase),
+                    user_private: encryption.map(|e| Arc::new(e) as _),
+                },
+                pos: None,
+                stream_index: track.stream_index as isize,
+                is_key,
+                is_corrupted,
+            })
+        })
+        .collect()
+}
+
+// ContentEncodingScope bits
+const SCOPE_FRAMES: u64 = 0x1;
+const SCOPE_PRIVATE: u64 = 0x2;
+
+// Largest frame or CodecPrivate inflated from zlib, beyond any real video frame
+const MAX_INFLATED_SIZE: u64 = 64 << 20;
+
+// The CodecPrivate, with its ContentEncodings removed
+fn track_codec_private(t: &TrackEntry) -> Option<Vec<u8>> {
+    let private = t.codec_private.as_ref()?;
+
+    match t.content_encodings {
+        Some(ref encodings) => match decode_content(encodings, SCOPE_PRIVATE, private, None) {
+            Ok((data, _)) => Some(data),
+            Err(e) => {
+                error!(
+                    "cannot decode CodecPrivate of track {}: {e}",
+                    t.track_number
+                );
+                Some(private.clone())
+            }
+        },
+        None => Some(private.clone()),
+    }
+}
+
+// Reverts the ContentEncodings applying to `scope`, from the highest order to the lowest.
+//
+// Encrypted data is decrypted if `keys` provides its key, otherwise it is returned
+// as is, along with its encryption parameters.
+fn decode_content(
+    encodings: &ContentEncodings,
+    scope: u64,
+    data: &[u8],
+    keys: Option<&KeyProvider>,
+) -> io::Result<(Vec<u8>, Option<EncryptionInfo>)> {
+    let mut encodings = encodings
+        .content_encoding
+        .iter()
+        .filter(|e| e.scope & scope != 0)
+        .collect::<Vec<_>>();
+    encodings.sort_by_key(|e| std::cmp::Reverse(e.order));
+
+    let mut data = data.to_vec();
+    for encoding in encodings {
+        if encoding.encoding_type == 1 {
+            let encryption = encoding
+                .encryption
+                .as_ref()
+                .ok_or_else(|| invalid_data("missing ContentEncryption"))?;
+
+            let (payload, info) = decrypt(encryption, &data, keys)?;
+            if info.is_some() {
+                // The remaining encodings apply to the decrypted data
+                return Ok((payload, info));
+            }
+
+            data = payload;
+            continue;
+        }
+
+        let (algo, settings) = encoding
+            .compression
+            .as_ref()
+            .map_or((0, None), |c| (c.algo, c.settings.as_deref()));
+
+        data = match algo {
+            0 => {
+                // A few bytes can inflate to gigabytes
+                let mut out = Vec::new();
+                ZlibDecoder::new(&data[..])
+                    .take(MAX_INFLATED_SIZE + 1)
+                    .read_to_end(&mut out)?;
+                if out.len() as u64 > MAX_INFLATED_SIZE {
+                    return Err(invalid_data("inflated data too large"));
+                }
+                out
+            }
+            // Header stripping
+            3 => [settings.unwrap_or_default(), &data].concat(),
+            algo => {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    format!("unsupported ContentCompAlgo {algo}"),
+                ))
+            }
+        };
+    }
+
+    Ok((data, None))
+}
+
+// Decrypts a frame following the WebM encryption layout: a signal byte,
+// then for encrypted frames an 8 bytes IV and the optional partition offsets.
+fn decrypt(
+    encryption: &ContentEncryption,
+    data: &[u8],
+    keys: Option<&KeyProvider>,
+) -> io::Result<(Vec<u8>, Option<EncryptionInfo>)> {
+    // Only AES in CTR mode is used by WebM
+    let cipher_mode = encryption
+        .aes_settings
+        .as_ref()
+        .map_or(1, |s| s.cipher_mode);
+    if encryption.enc_algo != 5 || cipher_mode != 1 {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!(
+                "unsupported ContentEncAlgo {} with cipher mode {cipher_mode}",
+                encryption.enc_algo
+            ),
+        ));
+    }
+
+    let (&signal, data) = data
+        .split_first()
+        .ok_or_else(|| invalid_data("missing signal byte"))?;
+
+    // Clear frame
+    if signal & 0x01 == 0 {
+        return Ok((data.to_vec(), None));
+    }
+
+    if data.len() < 8 {
+        return Err(invalid_data("missing IV"));
+    }
+    let (iv, data) = data.split_at(8);
+    let iv: [u8; 8] = iv.try_into().unwrap();
+
+    let (partitions, payload) = if signal & 0x02 != 0 {
+        let (&count, data) = data
+            .split_first()
+            .ok_or_else(|| invalid_data("missing partition count"))?;
+        let len = usize::from(count) * 4;
+        if data.len() < len {
+            return Err(invalid_data("missing partition offsets"));
+        }
+
+        let (offsets, payload) = data.split_at(len);
+        let offsets = offsets
+            .chunks(4)
+            .map(|o| u32::from_be_bytes(o.try_into().unwrap()))
+            .collect::<Vec<_>>();
+        if offsets.windows(2).any(|w| w[0] > w[1])
+            || offsets.iter().any(|&o| o as usize > payload.len())
+        {
+            return Err(invalid_data("invalid partition offsets"));
+        }
+
+        (offsets, payload)
+    } else {
+        (Vec::new(), data)
+    };
+
+    let key_id = encryption.enc_key_id.clone().unwrap_or_default();
+    let mut payload = payload.to_vec();
+
+    let Some(key) = keys.and_then(|k| k.key(&key_id)) else {
+        let info = EncryptionInfo {
+            key_id,
+            iv,
+            partitions,
+        };
+        return Ok((payload, Some(info)));
+    };
+
+    let mut counter = [0; 16];
+    counter[..8].copy_from_slice(&iv);
+    let mut cipher = Ctr64BE::<Aes128>::new(&key.into(), &counter.into());
+
+    if partitions.is_empty() {
+        cipher.apply_keystream(&mut payload);
+    } else {
+        // The encrypted partitions are the odd ones, and form a single keystream
+        let bounds = iter::once(0)
+            .chain(partitions.iter().map(|&o| o as usize))
+            .chain(iter::once(payload.len()))
+            .collect::<Vec<_>>();
+        for range in bounds.windows(2).skip(1).step_by(2) {
+            cipher.apply_keystream(&mut payload[range[0]..range[1]]);
+        }
+    }
+
+    Ok((payload, None))
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
 }
 
 struct Des {
@@ -315,18 +1463,19 @@ pub const MKV_DESC: &dyn Descriptor<OutputDemuxer = MkvDemuxer> = &Des {
 #[cfg(test)]
 #[allow(non_upper_case_globals)]
 mod tests {
-    use std::io::Cursor;
+    use std::io::{BufRead, Cursor, Seek as _};
 
     use nom::Offset;
 
     use av_format::{buffer::*, demuxer::Context};
     use uuid::Uuid;
 
-    use crate::elements::Seek;
+    use crate::elements::{BlockGroup, CuePoint, CueTrackPositions, Seek};
 
     use super::*;
 
     const webm: &[u8] = include_bytes!("../assets/bbb-vp9-opus.webm");
+    const trailer: &[u8] = include_bytes!("../assets/big-buck-bunny_trailer.webm");
 
     #[test]
     fn parse_headers() {
@@ -510,4 +1659,916 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn block_group_packets() {
+        let mut demuxer = MkvDemuxer::new();
+        demuxer.parse_until_tracks(webm).unwrap();
+
+        // Track 1, relative timestamp 5, no lacing
+        let keyframe = [0x81, 0x00, 0x05, 0x00, 0xAA, 0xBB];
+        let interframe = [0x81, 0x00, 0x0A, 0x00, 0xCC];
+
+        let group = |block, reference_block| BlockGroup {
+            block,
+            block_duration: Some(5),
+            reference_priority: 0,
+            reference_block,
+            codec_state: None,
+            discard_padding: None,
+        };
+
+        let cluster = Cluster {
+            timestamp: 1000,
+            position: None,
+            prev_size: None,
+            blocks: vec![
+                ClusterBlock::BlockGroup(group(&keyframe, None)),
+                ClusterBlock::BlockGroup(group(&interframe, Some(5))),
+            ],
+        };
+
+        let packets: Vec<_> = cluster
+            .generate_packets(
+                demuxer.info.as_ref().unwrap(),
+                demuxer.tracks.as_ref().unwrap(),
+                None,
+            )
+            .into_iter()
+            .map(|e| match e {
+                Event::NewPacket(p) => {
+                    assert_eq!(p.t.timebase, Some(Rational64::new(1, 1000)));
+                    (p.data, p.t.pts, p.t.duration, p.is_key)
+                }
+                e => panic!("unexpected event {e:?}"),
+            })
+            .collect();
+
+        assert_eq!(
+            packets,
+            vec![
+                (vec![0xAA, 0xBB], Some(1005), Some(5), true),
+                (vec![0xCC], Some(1010), Some(5), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn content_encodings() {
+        use std::io::Write;
+
+        use flate2::{write::ZlibEncoder, Compression};
+
+        use crate::elements::{ContentCompression, ContentEncoding};
+
+        let mut demuxer = MkvDemuxer::new();
+        demuxer.parse_until_tracks(webm).unwrap();
+
+        let zlib = |data: &[u8]| {
+            let mut e = ZlibEncoder::new(Vec::new(), Compression::default());
+            e.write_all(data).unwrap();
+            e.finish().unwrap()
+        };
+        let encoding = |order, scope, algo, settings| ContentEncoding {
+            order,
+            scope,
+            encoding_type: 0,
+            compression: Some(ContentCompression { algo, settings }),
+            encryption: None,
+        };
+
+        // The header is stripped first, then the frames and the CodecPrivate are compressed
+        let track = &mut demuxer.tracks.as_mut().unwrap().tracks[0];
+        track.codec_private = Some(zlib(&[1, 2, 3]));
+        track.content_encodings = Some(ContentEncodings {
+            content_encoding: vec![encoding(1, 3, 0, None), encoding(0, 1, 3, Some(vec![0xAA]))],
+        });
+
+        let stream = track_to_stream(demuxer.info.as_ref().unwrap(), track);
+        assert_eq!(stream.params.extradata, Some(vec![1, 2, 3]));
+
+        let block = [&[0x81, 0x00, 0x00, 0x80][..], &zlib(&[0xBB, 0xCC])].concat();
+        let cluster = Cluster {
+            timestamp: 0,
+            position: None,
+            prev_size: None,
+            blocks: vec![ClusterBlock::SimpleBlock(&block)],
+        };
+
+        let packets = cluster.generate_packets(
+            demuxer.info.as_ref().unwrap(),
+            demuxer.tracks.as_ref().unwrap(),
+            None,
+        );
+        match &packets[..] {
+            [Event::NewPacket(p)] => {
+                assert_eq!(p.data, vec![0xAA, 0xBB, 0xCC]);
+                assert!(!p.is_corrupted);
+            }
+            e => panic!("unexpected events {e:?}"),
+        }
+
+        // Inflating is bounded
+        let encodings = ContentEncodings {
+            content_encoding: vec![encoding(0, 1, 0, None)],
+        };
+        let bomb = zlib(&vec![0; MAX_INFLATED_SIZE as usize + 1]);
+        assert!(decode_content(&encodings, SCOPE_FRAMES, &bomb, None).is_err());
+        let frame = zlib(&vec![0; 1000]);
+        let (data, _) = decode_content(&encodings, SCOPE_FRAMES, &frame, None).unwrap();
+        assert_eq!(data.len(), 1000);
+    }
+
+    #[test]
+    fn encrypted_frames() {
+        use crate::elements::{ContentEncAesSettings, ContentEncoding};
+
+        let mut demuxer = MkvDemuxer::new();
+        demuxer.parse_until_tracks(webm).unwrap();
+
+        let track = &mut demuxer.tracks.as_mut().unwrap().tracks[0];
+        track.content_encodings = Some(ContentEncodings {
+            content_encoding: vec![ContentEncoding {
+                order: 0,
+                scope: 1,
+                encoding_type: 1,
+                compression: None,
+                encryption: Some(ContentEncryption {
+                    enc_algo: 5,
+                    enc_key_id: Some(vec![0x01, 0x02]),
+                    aes_settings: Some(ContentEncAesSettings { cipher_mode: 1 }),
+                }),
+            }],
+        });
+
+        let key = [0x42; 16];
+        let iv = [0, 1, 2, 3, 4, 5, 6, 7];
+        let encrypt = |data: &mut [u8]| {
+            let mut counter = [0; 16];
+            counter[..8].copy_from_slice(&iv);
+            Ctr64BE::<Aes128>::new(&key.into(), &counter.into()).apply_keystream(data)
+        };
+
+        // Fully encrypted
+        let mut full = b"secret".to_vec();
+        encrypt(&mut full);
+        let full = [&[0x81, 0x00, 0x00, 0x80, 0x01][..], &iv, &full].concat();
+
+        // Only bytes 2..4 and 6..8 are encrypted, as one keystream
+        let mut encrypted = b"ccssccss".to_vec();
+        let mut partitions = [&encrypted[2..4], &encrypted[6..8]].concat();
+        encrypt(&mut partitions);
+        encrypted[2..4].copy_from_slice(&partitions[..2]);
+        encrypted[6..8].copy_from_slice(&partitions[2..]);
+        #[rustfmt::skip]
+        let offsets = [0x03, 0, 0, 0, 0x02, 0, 0, 0, 0x04, 0, 0, 0, 0x06];
+        let partitioned = [
+            &[0x81, 0x00, 0x00, 0x80, 0x03][..],
+            &iv,
+            &offsets,
+            &encrypted,
+        ]
+        .concat();
+
+        // Clear
+        let clear = [0x81, 0x00, 0x00, 0x80, 0x00, b'c'];
+
+        let cluster = Cluster {
+            timestamp: 0,
+            position: None,
+            prev_size: None,
+            blocks: vec![
+                ClusterBlock::SimpleBlock(&full),
+                ClusterBlock::SimpleBlock(&partitioned),
+                ClusterBlock::SimpleBlock(&clear),
+            ],
+        };
+
+        let packets = |keys: Option<&KeyProvider>| {
+            cluster
+                .generate_packets(
+                    demuxer.info.as_ref().unwrap(),
+                    demuxer.tracks.as_ref().unwrap(),
+                    keys,
+                )
+                .into_iter()
+                .map(|e| match e {
+                    Event::NewPacket(p) => {
+                        assert!(!p.is_corrupted);
+                        let info =
+                            p.t.user_private
+                                .map(|u| u.downcast_ref::<EncryptionInfo>().unwrap().clone());
+                        (p.data, info)
+                    }
+                    e => panic!("unexpected event {e:?}"),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let keys = KeyProvider::new(move |id| (id == [0x01, 0x02]).then_some(key));
+        assert_eq!(
+            packets(Some(&keys)),
+            vec![
+                (b"secret".to_vec(), None),
+                (b"ccssccss".to_vec(), None),
+                (b"c".to_vec(), None),
+            ]
+        );
+
+        let info = |partitions| EncryptionInfo {
+            key_id: vec![0x01, 0x02],
+            iv,
+            partitions,
+        };
+        let unknown = KeyProvider::new(|_| None);
+        assert_eq!(
+            packets(Some(&unknown)),
+            vec![
+                (full[13..].to_vec(), Some(info(vec![]))),
+                (encrypted, Some(info(vec![2, 4, 6]))),
+                (b"c".to_vec(), None),
+            ]
+        );
+    }
+
+    #[test]
+    fn absolute_timestamps() {
+        let mut context = Context::new(MkvDemuxer::new(), AccReader::new(Cursor::new(trailer)));
+        context.read_headers().unwrap();
+
+        let mut video_pts = Vec::new();
+        while let Ok(event) = context.read_event() {
+            match event {
+                Event::NewPacket(p) if p.stream_index == 0 => video_pts.push(p.t.pts.unwrap()),
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        assert!(video_pts.windows(2).all(|w| w[0] < w[1]));
+
+        let mut demuxer = MkvDemuxer::new();
+        demuxer.parse_until_tracks(webm).unwrap();
+        let info = demuxer.info.unwrap();
+        let mut track = demuxer.tracks.unwrap().tracks.remove(0);
+        assert_eq!(track_timebase(&info, &track), Rational64::new(1, 1000));
+        track.track_timestamp_scale = 1.0000005;
+        assert_eq!(
+            track_timebase(&info, &track),
+            Rational64::new(2000001, 2_000_000_000)
+        );
+    }
+
+    #[test]
+    fn seek_cues() {
+        let mut demuxer = MkvDemuxer::new();
+        let mut info = GlobalInfo {
+            duration: None,
+            timebase: None,
+            streams: Vec::new(),
+        };
+        let mut buf = AccReader::new(Cursor::new(webm));
+        buf.fill_buf().unwrap();
+        demuxer.read_headers(&mut buf, &mut info).unwrap();
+
+        assert_eq!(demuxer.segment_offset, 55);
+        assert_eq!(demuxer.cues_position(), Some(55 + 23267));
+
+        let flags = SeekFlags {
+            keyframe: true,
+            backward: true,
+        };
+        assert_eq!(demuxer.seek(&mut buf, 0, 1000, flags).unwrap(), 528);
+        assert_eq!(demuxer.cues.as_ref().unwrap().cue_points.len(), 1);
+        assert_eq!(buf.stream_position().unwrap(), 528);
+    }
+
+    #[test]
+    fn seek_bisect() {
+        // Clusters every 20 ticks, with a keyframe in every other one, and
+        // their Timestamp after a Void
+        let mut file = webm[..528].to_vec();
+        for n in 0..8u8 {
+            let flags = if n % 2 == 0 { 0x80 } else { 0x00 };
+            file.extend_from_slice(&[
+                0x1F,
+                0x43,
+                0xB6,
+                0x75,
+                0x8D,
+                0xEC,
+                0x81,
+                0x00,
+                0xE7,
+                0x81,
+                n * 20,
+                0xA3,
+                0x85,
+                0x81,
+                0x00,
+                0x00,
+                flags,
+                0x00,
+            ]);
+        }
+        let cluster = |n: u64| 528 + n * 18;
+
+        let mut demuxer = MkvDemuxer::new();
+        let mut info = GlobalInfo {
+            duration: None,
+            timebase: None,
+            streams: Vec::new(),
+        };
+        let mut buf = AccReader::new(Cursor::new(file));
+        buf.fill_buf().unwrap();
+        demuxer.read_headers(&mut buf, &mut info).unwrap();
+        demuxer.seek_head = None;
+
+        let backward = SeekFlags {
+            keyframe: false,
+            backward: true,
+        };
+        let forward = SeekFlags::default();
+
+        assert_eq!(demuxer.seek(&mut buf, 0, 0, backward).unwrap(), cluster(0));
+        assert_eq!(demuxer.seek(&mut buf, 0, 50, backward).unwrap(), cluster(2));
+        assert_eq!(
+            demuxer.seek(&mut buf, 0, 500, backward).unwrap(),
+            cluster(7)
+        );
+        assert_eq!(demuxer.seek(&mut buf, 0, 50, forward).unwrap(), cluster(3));
+        assert_eq!(demuxer.seek(&mut buf, 0, 60, forward).unwrap(), cluster(3));
+        assert!(demuxer.seek(&mut buf, 0, 500, forward).is_err());
+
+        // Packets before the next keyframe are dropped
+        let keyframe = SeekFlags {
+            keyframe: true,
+            backward: false,
+        };
+        assert_eq!(demuxer.seek(&mut buf, 0, 60, keyframe).unwrap(), cluster(3));
+        let packet = loop {
+            buf.fill_buf().unwrap();
+            let (seek, event) = demuxer.read_event(&mut buf).unwrap();
+            buf.seek(seek).unwrap();
+            match event {
+                Event::NewPacket(p) => break p,
+                Event::MoreDataNeeded(_) => {}
+                e => panic!("unexpected event: {e:?}"),
+            }
+        };
+        assert!(packet.is_key);
+        assert_eq!(packet.t.pts, Some(80));
+        assert_eq!(demuxer.seek_keyframe, None);
+
+        // Going backward, the Clusters without a keyframe before the target are passed
+        let backward_keyframe = SeekFlags {
+            keyframe: true,
+            backward: true,
+        };
+        assert_eq!(
+            demuxer.seek(&mut buf, 0, 70, backward_keyframe).unwrap(),
+            cluster(2)
+        );
+        let packet = loop {
+            buf.fill_buf().unwrap();
+            let (seek, event) = demuxer.read_event(&mut buf).unwrap();
+            buf.seek(seek).unwrap();
+            match event {
+                Event::NewPacket(p) => break p,
+                Event::MoreDataNeeded(_) => {}
+                e => panic!("unexpected event: {e:?}"),
+            }
+        };
+        assert!(packet.is_key);
+        assert_eq!(packet.t.pts, Some(40));
+        assert_eq!(
+            demuxer.seek(&mut buf, 0, 40, backward_keyframe).unwrap(),
+            cluster(2)
+        );
+        assert_eq!(
+            demuxer.seek(&mut buf, 0, 20, backward_keyframe).unwrap(),
+            cluster(0)
+        );
+
+        // Past the last CuePoint, the following Clusters are searched
+        let cue_point = |n: u64| CuePoint {
+            time: n * 20,
+            track_positions: vec![CueTrackPositions {
+                track: 1,
+                cluster_position: cluster(n) - demuxer.segment_offset,
+                relative_position: None,
+                duration: None,
+                block_number: None,
+                codec_state: 0,
+                references: Vec::new(),
+            }],
+        };
+        demuxer.cues = Some(Cues {
+            cue_points: vec![cue_point(0), cue_point(2)],
+        });
+        assert_eq!(demuxer.seek(&mut buf, 0, 20, forward).unwrap(), cluster(2));
+        assert_eq!(demuxer.seek(&mut buf, 0, 90, forward).unwrap(), cluster(5));
+        assert_eq!(demuxer.seek(&mut buf, 0, 90, backward).unwrap(), cluster(2));
+    }
+
+    #[test]
+    fn unknown_size_clusters() {
+        // Live streams use unknown sizes for the Segment and the Clusters
+        let mut live = webm[..528].to_vec();
+        live[47..55].copy_from_slice(&[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]);
+        for n in 0..3u8 {
+            live.extend_from_slice(&[
+                0x1F,
+                0x43,
+                0xB6,
+                0x75,
+                0xFF,
+                0xE7,
+                0x81,
+                n * 20,
+                0xA3,
+                0x85,
+                0x81,
+                0x00,
+                0x00,
+                0x80,
+                0x00,
+            ]);
+        }
+
+        let mut context = Context::new(MkvDemuxer::new(), AccReader::new(Cursor::new(live)));
+        context.read_headers().unwrap();
+
+        let mut pts = Vec::new();
+        while let Ok(event) = context.read_event() {
+            match event {
+                Event::NewPacket(p) => pts.push(p.t.pts.unwrap()),
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        assert_eq!(pts, vec![0, 20, 40]);
+    }
+
+    #[test]
+    fn compatibility_tracks() {
+        use av_data::params::{AudioInfo, VideoInfo};
+
+        use crate::muxer::stream_to_track;
+
+        let info = Info {
+            timestamp_scale: 1000000,
+            ..Default::default()
+        };
+        let stream = |kind, codec_id: &str| Stream {
+            id: 1,
+            index: 0,
+            start: None,
+            duration: None,
+            timebase: Rational64::new(1, 1000),
+            params: CodecParams {
+                kind: Some(kind),
+                codec_id: Some(codec_id.into()),
+                extradata: Some(vec![0xAA]),
+                bit_rate: 128000,
+                convergence_window: 0,
+                delay: 0,
+            },
+            user_private: None,
+        };
+
+        let video = MediaKind::Video(VideoInfo {
+            width: 640,
+            height: 480,
+            format: None,
+        });
+        let track = stream_to_track(&stream(video, "mjpeg"));
+        assert_eq!(track.codec_id, "V_MS/VFW/FOURCC");
+        let (_, header) = BitmapInfoHeader::parse(track.codec_private.as_ref().unwrap()).unwrap();
+        assert_eq!((header.bit_count, header.size_image), (0, 0));
+        let params = track_to_stream(&info, &track).params;
+        assert_eq!(params.codec_id.as_deref(), Some("mjpeg"));
+        assert_eq!(params.extradata, Some(vec![0xAA]));
+        match params.kind {
+            Some(MediaKind::Video(v)) => assert_eq!((v.width, v.height), (640, 480)),
+            k => panic!("unexpected kind {k:?}"),
+        }
+
+        let audio = MediaKind::Audio(AudioInfo {
+            rate: 44100,
+            map: default_channel_map(2),
+            format: None,
+        });
+        let track = stream_to_track(&stream(audio.clone(), "wmav2"));
+        assert_eq!(track.codec_id, "A_MS/ACM");
+        let (_, wf) = WaveFormatEx::parse(track.codec_private.as_ref().unwrap()).unwrap();
+        assert_eq!((wf.block_align, wf.bits_per_sample), (0, 0));
+        let params = track_to_stream(&info, &track).params;
+        assert_eq!(params.codec_id.as_deref(), Some("wmav2"));
+        assert_eq!(params.extradata, Some(vec![0xAA]));
+        assert_eq!(params.bit_rate, 128000);
+        match params.kind {
+            Some(MediaKind::Audio(a)) => {
+                assert_eq!(a.rate, 44100);
+                assert_eq!(a.map.unwrap().len(), 2);
+            }
+            k => panic!("unexpected kind {k:?}"),
+        }
+
+        // The block size of PCM is derived from its samples
+        let alaw = MediaKind::Audio(AudioInfo {
+            rate: 8000,
+            map: default_channel_map(2),
+            format: Some(Arc::new(Soniton::new(8, false, false, false, false, false))),
+        });
+        let track = stream_to_track(&stream(alaw, "pcm_alaw"));
+        let (_, wf) = WaveFormatEx::parse(track.codec_private.as_ref().unwrap()).unwrap();
+        assert_eq!((wf.block_align, wf.bits_per_sample), (2, 8));
+    }
+
+    #[test]
+    fn audio_params() {
+        use av_data::params::AudioInfo;
+
+        use crate::muxer::stream_to_track;
+
+        let info = Info {
+            timestamp_scale: 1000000,
+            ..Default::default()
+        };
+        let stream = |audio, codec_id: &str, extradata| Stream {
+            id: 1,
+            index: 0,
+            start: None,
+            duration: None,
+            timebase: Rational64::new(1, 1000),
+            params: CodecParams {
+                kind: Some(MediaKind::Audio(audio)),
+                codec_id: Some(codec_id.into()),
+                extradata,
+                bit_rate: 0,
+                convergence_window: 0,
+                delay: 0,
+            },
+            user_private: None,
+        };
+
+        let pcm = AudioInfo {
+            rate: 48000,
+            map: default_channel_map(6),
+            format: Some(Arc::new(Soniton::new(24, false, false, false, false, true))),
+        };
+        let track = stream_to_track(&stream(pcm.clone(), "pcm_int_le", None));
+        let audio = track.audio.as_ref().unwrap();
+        assert_eq!(audio.channels, 6);
+        assert_eq!(audio.bit_depth, Some(24));
+        match track_to_stream(&info, &track).params.kind {
+            Some(MediaKind::Audio(a)) => assert_eq!(a, pcm),
+            k => panic!("unexpected kind {k:?}"),
+        }
+
+        // AAC LC at 24 kHz, with an implicit SBR extension
+        let aac = AudioInfo {
+            rate: 48000,
+            map: default_channel_map(2),
+            format: None,
+        };
+        let track = stream_to_track(&stream(aac, "aac", Some(vec![0x13, 0x10])));
+        let audio = track.audio.as_ref().unwrap();
+        assert_eq!(audio.channels, 2);
+        assert_eq!(audio.sampling_frequency, 24000.0);
+        assert_eq!(audio.output_sampling_frequency, Some(48000.0));
+        match track_to_stream(&info, &track).params.kind {
+            Some(MediaKind::Audio(a)) => {
+                assert_eq!(a.rate, 48000);
+                assert_eq!(a.map, default_channel_map(2));
+            }
+            k => panic!("unexpected kind {k:?}"),
+        }
+    }
+
+    #[test]
+    fn subtitle_tracks() {
+        use crate::muxer::stream_to_track;
+
+        let info = Info {
+            timestamp_scale: 1000000,
+            ..Default::default()
+        };
+        let header = b"[Script Info]\nScriptType: v4.00+\n".to_vec();
+        let stream = Stream {
+            id: 3,
+            index: 2,
+            start: None,
+            duration: None,
+            timebase: Rational64::new(1, 1000),
+            params: CodecParams {
+                kind: None,
+                codec_id: Some("ass".into()),
+                extradata: Some(header.clone()),
+                bit_rate: 0,
+                convergence_window: 0,
+                delay: 0,
+            },
+            user_private: None,
+        };
+
+        let track = stream_to_track(&stream);
+        assert_eq!(track.codec_id, "S_TEXT/ASS");
+        assert_eq!(TrackType::from(track.track_type), TrackType::Subtitle);
+        assert_eq!(track.flag_lacing, 0);
+
+        let params = track_to_stream(&info, &track).params;
+        assert_eq!(params.codec_id.as_deref(), Some("ass"));
+        assert_eq!(params.kind, None);
+        assert_eq!(params.extradata, Some(header));
+
+        for track_type in [TrackType::Metadata, TrackType::Other(0x42)] {
+            assert_eq!(TrackType::from(u64::from(track_type)), track_type);
+        }
+        assert_eq!(
+            TrackType::from_codec_id("S_TEXT/WEBVTT"),
+            Some(TrackType::Subtitle)
+        );
+    }
+
+    #[test]
+    fn track_properties() {
+        use av_data::{
+            params::VideoInfo,
+            pixel::formats::{YUV411, YUV420},
+        };
+
+        use crate::muxer::{stream_to_track, TrackProperties};
+
+        let info = Info {
+            timestamp_scale: 1000000,
+            ..Default::default()
+        };
+        let stream = |format, properties: Option<TrackProperties>| Stream {
+            id: 1,
+            index: 0,
+            start: None,
+            duration: None,
+            timebase: Rational64::new(1, 1000),
+            params: CodecParams {
+                kind: Some(MediaKind::Video(VideoInfo {
+                    width: 720,
+                    height: 480,
+                    format,
+                })),
+                codec_id: Some("vp9".into()),
+                extradata: None,
+                bit_rate: 0,
+                convergence_window: 0,
+                delay: 0,
+            },
+            user_private: properties.map(|p| Arc::new(p) as _),
+        };
+
+        // Nothing is known about the colours
+        let track = stream_to_track(&stream(None, None));
+        assert_eq!(
+            (track.flag_enabled, track.flag_default, track.flag_forced),
+            (1, 1, 0)
+        );
+        assert_eq!(track.language, "und");
+        assert_eq!(track.default_duration, None);
+        let video = track.video.as_ref().unwrap();
+        assert_eq!((video.display_width, video.display_height), (None, None));
+        assert_eq!(video.colour, None);
+
+        let mut format = *YUV420;
+        format.model = ColorModel::Trichromatic(TrichromaticEncodingSystem::YUV(YUVSystem::YCbCr(
+            YUVRange::Full,
+        )));
+        format.matrix = MatrixCoefficients::BT709;
+        format.chroma_location = ChromaLocation::Left;
+        let properties = TrackProperties {
+            language: Some("fr-CA".into()),
+            name: Some("Commentaire".into()),
+            default: false,
+            forced: true,
+            sample_aspect_ratio: Some(Rational64::new(4, 3)),
+            frame_rate: Some(Rational64::new(30000, 1001)),
+        };
+        let track = stream_to_track(&stream(Some(Arc::new(format)), Some(properties)));
+        assert_eq!((track.flag_default, track.flag_forced), (0, 1));
+        assert_eq!(track.name.as_deref(), Some("Commentaire"));
+        assert_eq!(track.language, "und");
+        assert_eq!(track.language_ietf.as_deref(), Some("fr-CA"));
+        assert_eq!(track.default_duration, Some(33366667));
+        let video = track.video.as_ref().unwrap();
+        assert_eq!(
+            (video.display_width, video.display_height),
+            (Some(960), Some(480))
+        );
+        let colour = video.colour.as_ref().unwrap();
+        assert_eq!((colour.matrix_coefficients, colour.range), (1, 2));
+        assert_eq!((colour.transfer_characteristics, colour.primaries), (2, 2));
+        assert_eq!(
+            (colour.chroma_siting_horz, colour.chroma_siting_vert),
+            (1, 2)
+        );
+        assert_eq!(colour.bits_per_channel, 8);
+        assert_eq!(
+            (
+                colour.chroma_subsampling_horz,
+                colour.chroma_subsampling_vert
+            ),
+            (Some(1), Some(1))
+        );
+        match track_to_stream(&info, &track).params.kind {
+            Some(MediaKind::Video(v)) => assert_eq!(v.format.as_deref(), Some(&format)),
+            k => panic!("unexpected kind {k:?}"),
+        }
+
+        // 4:1:1 drops 3 chroma samples out of 4
+        let track = stream_to_track(&stream(Some(Arc::new(*YUV411)), None));
+        let colour = track.video.unwrap().colour.unwrap();
+        assert_eq!(
+            (
+                colour.chroma_subsampling_horz,
+                colour.chroma_subsampling_vert
+            ),
+            (Some(3), Some(0))
+        );
+
+        // ISO 639-2 codes only need the Language
+        let properties = TrackProperties {
+            language: Some("fre".into()),
+            sample_aspect_ratio: Some(Rational64::new(8, 9)),
+            ..Default::default()
+        };
+        let track = stream_to_track(&stream(None, Some(properties)));
+        assert_eq!(track.language, "fre");
+        assert_eq!(track.language_ietf, None);
+        let video = track.video.as_ref().unwrap();
+        assert_eq!(
+            (video.display_width, video.display_height),
+            (Some(720), Some(540))
+        );
+    }
+
+    #[test]
+    fn track_selection() {
+        let selected = |selectors| {
+            let mut demuxer = MkvDemuxer::with_params(DemuxerParams {
+                track_numbers: None,
+                selectors,
+            });
+            demuxer.parse_until_tracks(webm).unwrap();
+            demuxer
+                .tracks
+                .unwrap()
+                .tracks
+                .into_iter()
+                .map(|t| t.codec_id)
+                .collect::<Vec<_>>()
+        };
+
+        let audio = TrackSelector {
+            track_type: Some(TrackType::Audio),
+            ..Default::default()
+        };
+        assert_eq!(selected(vec![audio]), ["A_OPUS"]);
+
+        let first_video = TrackSelector {
+            track_type: Some(TrackType::Video),
+            limit: Some(1),
+            ..Default::default()
+        };
+        let opus = TrackSelector {
+            codec_id: Some("opus".into()),
+            default: Some(true),
+            ..Default::default()
+        };
+        assert_eq!(selected(vec![first_video, opus]), ["V_VP9", "A_OPUS"]);
+
+        let first_of_each = TrackSelector {
+            limit: Some(1),
+            ..Default::default()
+        };
+        assert_eq!(selected(vec![first_of_each]), ["V_VP9", "A_OPUS"]);
+
+        let forced = TrackSelector {
+            forced: Some(true),
+            ..Default::default()
+        };
+        assert!(selected(vec![forced]).is_empty());
+
+        let french = TrackSelector {
+            language: Some("fr".into()),
+            ..Default::default()
+        };
+        assert!(selected(vec![french]).is_empty());
+
+        let language = |lang: &str| TrackSelector {
+            language: Some(lang.into()),
+            ..Default::default()
+        };
+        let track = |language: &str, ietf: Option<&str>| TrackEntry {
+            language: language.into(),
+            language_ietf: ietf.map(Into::into),
+            ..Default::default()
+        };
+        assert!(language("en").matches(&track("eng", None)));
+        assert!(language("fre").matches(&track("fra", None)));
+        assert!(!language("en").matches(&track("fre", None)));
+        assert!(language("pt-BR").matches(&track("und", Some("pt-BR"))));
+        assert!(language("por").matches(&track("und", Some("pt-BR"))));
+        assert!(!language("pt-PT").matches(&track("und", Some("pt-BR"))));
+        assert!(language("ger").matches(&track("ger", Some("de-CH"))));
+        assert!(language("eng").matches(&track("eng", Some("en-GB"))));
+        // The Language is ignored when there is a LanguageIETF
+        assert!(language("fr").matches(&track("eng", Some("fr"))));
+        assert!(!language("eng").matches(&track("eng", Some("fr"))));
+    }
+
+    #[test]
+    fn codec_config() {
+        let mut demuxer = MkvDemuxer::new();
+        demuxer.parse_until_tracks(webm).unwrap();
+
+        let tracks = &demuxer.tracks.as_ref().unwrap().tracks;
+        let opus = tracks.iter().find(|t| t.codec_id == "A_OPUS").unwrap();
+        let stream = track_to_stream(demuxer.info.as_ref().unwrap(), opus);
+
+        assert_eq!(stream.params.delay, 312);
+        match stream.params.kind {
+            Some(MediaKind::Audio(ref a)) => {
+                assert_eq!(a.rate, 48000);
+                assert_eq!(a.map.as_ref().unwrap().len(), 1);
+            }
+            ref k => panic!("unexpected kind {k:?}"),
+        }
+
+        let config = stream.user_private.unwrap();
+        match config.downcast_ref::<CodecConfig>() {
+            Some(CodecConfig::Opus(head)) => assert_eq!(head.pre_skip, 312),
+            c => panic!("unexpected config {c:?}"),
+        }
+    }
+
+    #[test]
+    fn colour_format() {
+        let colour = Colour {
+            matrix_coefficients: 9,
+            bits_per_channel: 10,
+            chroma_subsampling_horz: Some(1),
+            chroma_subsampling_vert: Some(0),
+            chroma_siting_horz: 1,
+            chroma_siting_vert: 2,
+            range: 2,
+            transfer_characteristics: 16,
+            primaries: 9,
+            ..Default::default()
+        };
+        let video = Video {
+            pixel_width: 1920,
+            pixel_height: 1080,
+            colour: Some(colour),
+            ..Default::default()
+        };
+
+        let format = match track_entry_video_kind(&video) {
+            Some(MediaKind::Video(v)) => v.format.unwrap(),
+            k => panic!("unexpected kind {k:?}"),
+        };
+        assert_eq!(
+            format.model,
+            ColorModel::Trichromatic(TrichromaticEncodingSystem::YUV(YUVSystem::YCbCr(
+                YUVRange::Full
+            )))
+        );
+        assert_eq!(
+            format.matrix,
+            MatrixCoefficients::BT2020NonConstantLuminance
+        );
+        assert_eq!(format.xfer, TransferCharacteristic::PerceptualQuantizer);
+        assert_eq!(format.primaries, ColorPrimaries::BT2020);
+        assert_eq!(format.chroma_location, ChromaLocation::Left);
+        assert_eq!(format.components, 3);
+        assert_eq!(format.get_total_depth(), 30);
+        let chroma = format.comp_info[1].unwrap();
+        assert_eq!((chroma.h_ss, chroma.v_ss), (1, 0));
+
+        // 4:1:1 drops 3 chroma samples out of 4
+        let colour_411 = Colour {
+            chroma_subsampling_horz: Some(3),
+            ..video.colour.clone().unwrap()
+        };
+        let chroma = super::colour_format(&colour_411).comp_info[1].unwrap();
+        assert_eq!((chroma.h_ss, chroma.v_ss), (2, 0));
+
+        let video = Video {
+            colour: None,
+            ..video
+        };
+        match track_entry_video_kind(&video) {
+            Some(MediaKind::Video(v)) => assert!(v.format.is_none()),
+            k => panic!("unexpected kind {k:?}"),
+        }
+    }
 }
@@ -72,6 +72,18 @@ pub enum ErrorKind {
 
     /// A CRC-32 element was found, but the checksum did not match.
     Crc32Mismatch,
+
+    /// The frame sizes declared in the lacing header of a Block add up to
+    /// more than the data available in the Block.
+    LaceSizeTooLarge,
+
+    /// An EBML laced Block declared a frame size difference that results in
+    /// a negative frame size.
+    NegativeLaceSize,
+
+    /// The data of a Block using fixed-size lacing cannot be divided evenly
+    /// between its frames.
+    FixedLaceSizeMismatch,
 }
 
 /// Create an error with the given ID and [ErrorKind].
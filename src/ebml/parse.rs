@@ -7,7 +7,7 @@ use nom::{
     combinator::{complete, map, map_res, opt},
     sequence::{preceded, tuple},
     Err::Incomplete,
-    Needed, Parser,
+    Needed, Offset, Parser,
 };
 use uuid::Uuid;
 
@@ -28,7 +28,6 @@ pub trait EbmlParsable<'a>: Sized {
 trait Int: From<u8> + Shl<Self, Output = Self> + BitOr<Self, Output = Self> {}
 impl Int for u64 {}
 impl Int for u32 {}
-impl Int for i64 {}
 
 impl<'a, T: Int> EbmlParsable<'a> for T {
     fn try_parse(data: &'a [u8]) -> Result<Self, ErrorKind> {
@@ -45,6 +44,21 @@ impl<'a, T: Int> EbmlParsable<'a> for T {
     }
 }
 
+// Signed integers are stored in two's complement, on as few bytes as needed
+impl<'a> EbmlParsable<'a> for i64 {
+    fn try_parse(data: &'a [u8]) -> Result<Self, ErrorKind> {
+        if data.len() > 8 {
+            return Err(ErrorKind::IntTooWide);
+        }
+
+        let val = data.iter().fold(0, |val, &b| (val << 8) | u64::from(b));
+        let unused = 64 - 8 * data.len() as u32;
+        Ok((val.checked_shl(unused).unwrap_or(0) as i64)
+            .checked_shr(unused)
+            .unwrap_or(0))
+    }
+}
+
 // FIXME: Define and double-check float parsing behaviour in error cases
 // FIXME: Also implement a test suite for that
 impl<'a> EbmlParsable<'a> for f64 {
@@ -160,7 +174,49 @@ pub fn skip_element(input: &[u8]) -> EbmlResult<u32> {
     Ok((i, id))
 }
 
-const CRC: Crc<u32> = Crc::<u32>::new(&Algorithm {
+/// Parses the data of a master Element with an unknown size, and returns
+/// the resulting Element.
+///
+/// The data extends up to the first Element whose ID is rejected by `is_child`,
+/// which is usually a sibling or a parent. If `at_eof` is set, the end of the
+/// input also ends the data, otherwise more input is requested.
+pub fn unknown_size_element<'a, O, F>(
+    is_child: F,
+    at_eof: bool,
+) -> impl Fn(&'a [u8]) -> EbmlResult<'a, O>
+where
+    O: EbmlParsable<'a>,
+    F: Fn(u32) -> bool,
+{
+    move |input| {
+        let (start, crc) = crc(input)?;
+
+        let mut i = start;
+        loop {
+            if i.is_empty() {
+                if at_eof {
+                    break;
+                }
+                return Err(Incomplete(Needed::new(1)));
+            }
+
+            let (_, id) = vid(i)?;
+            if !is_child(id) {
+                break;
+            }
+
+            i = skip_element(i)?.0;
+        }
+
+        let (_, data) = checksum(crc, take(start.offset(i)))(start)?;
+        match O::try_parse(data) {
+            Ok(o) => Ok((i, o)),
+            Err(kind) => Err(nom::Err::Error(Error { id: 0, kind })),
+        }
+    }
+}
+
+pub(crate) const CRC: Crc<u32> = Crc::<u32>::new(&Algorithm {
     init: 0xFFFFFFFF,
     ..crc::CRC_32_ISO_HDLC
 });
@@ -236,6 +292,19 @@ pub fn elem_size(input: &[u8]) -> EbmlResult<usize> {
     })(input)
 }
 
+/// Parses the Element Data Size of a master Element, which is `None`
+/// if it has the reserved unknown size, with all the VINT_DATA bits set.
+pub fn master_size(input: &[u8]) -> EbmlResult<'_, Option<u64>> {
+    let (i, size) = vint(input)?;
+    let len = input.offset(i) as u64;
+
+    if size == (1 << (7 * len)) - 1 {
+        Ok((i, None))
+    } else {
+        Ok((i, Some(size)))
+    }
+}
+
 // The ID are represented in the specification as their binary representation
 // do not drop the marker bit.
 pub fn vid(input: &[u8]) -> EbmlResult<u32> {
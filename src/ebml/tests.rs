@@ -35,6 +35,20 @@ fn webm_header() {
     assert_eq!(header, expected);
 }
 
+#[test]
+fn unknown_size() {
+    assert_eq!(master_size(&[0xFF]).unwrap().1, None);
+    assert_eq!(master_size(&[0x7F, 0xFF]).unwrap().1, None);
+    assert_eq!(
+        master_size(&[0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF])
+            .unwrap()
+            .1,
+        None
+    );
+    assert_eq!(master_size(&[0xFE]).unwrap().1, Some(126));
+    assert_eq!(master_size(&[0x40, 0x7F]).unwrap().1, Some(127));
+}
+
 #[test]
 fn floats() {
     #[rustfmt::skip]
@@ -97,6 +111,21 @@ fn variable_integer() {
     }
 }
 
+#[test]
+fn signed_integers() {
+    for (data, expected) in [
+        (&[][..], 0),
+        (&[0xEC], -20),
+        (&[0x7F], 127),
+        (&[0xFF, 0x38], -200),
+        (&[0x00, 0xC8], 200),
+        (&[0x80, 0, 0, 0, 0, 0, 0, 0], i64::MIN),
+    ] {
+        assert_eq!(i64::try_parse(data), Ok(expected));
+    }
+    assert!(i64::try_parse(&[0; 9]).is_err());
+}
+
 fn mkv_headers() -> Vec<(&'static str, EbmlHeader)> {
     vec![
         ("test1.mkv", default_header()), // basic
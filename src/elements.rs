@@ -1,33 +1,38 @@
 use nom::{
     bytes::streaming::take,
-    combinator::{map, map_opt, opt},
+    combinator::{complete, map, map_opt, opt},
     number::streaming::{be_i16, be_u8},
     sequence::{pair, tuple},
+    Offset,
 };
 
 pub use uuid::Uuid;
 
-use crate::ebml::{check_id, checksum, crc, elem_size, vid, vint, EbmlParsable, EbmlResult, Error};
+use crate::ebml::{
+    check_id, checksum, crc, ebml_element, ebml_err, elem_size, get_required, master_size,
+    skip_element, unknown_size_element, vid, vint, EbmlParsable, EbmlResult, Error, ErrorKind,
+    DEPRECATED,
+};
 use crate::ebml::{macros::impl_ebml_master, Date};
-use crate::elements;
 
 #[derive(Debug, Clone, PartialEq)]
 pub enum SegmentElement<'a> {
     SeekHead(SeekHead),
     Info(Info),
     Tracks(Tracks),
-    // Chapters(Chapters),
+    Chapters(Chapters),
     Cluster(Cluster<'a>),
-    // Cues(Cues),
-    // Attachments(Attachments),
-    // Tags(Tags),
+    Cues(Cues),
+    Attachments(Attachments),
+    Tags(Tags),
     Void(usize),
     Unknown(u32, Option<usize>),
 }
 
 // https://datatracker.ietf.org/doc/html/draft-lhomme-cellar-matroska-03#section-7.3.3
+/// Parses the Segment header, with a `None` size if it is unknown.
 pub fn segment(input: &[u8]) -> EbmlResult<(u32, Option<u64>)> {
-    pair(check_id(0x18538067), opt(vint))(input)
+    pair(check_id(0x18538067), master_size)(input)
 }
 
 pub(crate) fn sub_element<'a, O: EbmlParsable<'a>>(input: &'a [u8]) -> EbmlResult<'a, O> {
@@ -45,32 +50,64 @@ pub(crate) fn sub_element<'a, O: EbmlParsable<'a>>(input: &'a [u8]) -> EbmlResul
     }
 }
 
+// The EBML header, the Segment and the Segment children end the unknown-sized
+// Segment children
+fn is_top_level(id: u32) -> bool {
+    matches!(
+        id,
+        0x1A45DFA3
+            | 0x18538067
+            | 0x114D9B74
+            | 0x1549A966
+            | 0x1F43B675
+            | 0x1043A770
+            | 0x1254C367
+            | 0x1941A469
+            | 0x1654AE6B
+            | 0x1C53BB6B
+    )
+}
+
+// Parses a Segment child, whose size may be unknown
+fn master_element<'a, O: EbmlParsable<'a>>(input: &'a [u8], at_eof: bool) -> EbmlResult<'a, O> {
+    match master_size(input)? {
+        (i, None) => unknown_size_element(|id| !is_top_level(id), at_eof)(i),
+        (_, Some(_)) => sub_element(input),
+    }
+}
+
 // Segment, the root element, has id 0x18538067
 pub fn segment_element(input: &[u8]) -> EbmlResult<SegmentElement> {
+    parse_segment_element(input, false)
+}
+
+/// Parses a Segment child like [segment_element], where the end of the input
+/// also ends an unknown-sized Element, once no more data is available.
+pub fn segment_element_eof(input: &[u8]) -> EbmlResult<'_, SegmentElement<'_>> {
+    parse_segment_element(input, true)
+}
+
+fn parse_segment_element(input: &[u8], at_eof: bool) -> EbmlResult<'_, SegmentElement<'_>> {
     use SegmentElement::*;
 
-    vid(input).and_then(|(i, id)| {
-        match id {
-            0x114D9B74 => sub_element::<elements::SeekHead>(i).map(|(i, sh)| (i, SeekHead(sh))),
-            0x1549A966 => sub_element::<elements::Info>(i).map(|(i, info)| (i, Info(info))),
-            0x1F43B675 => sub_element::<elements::Cluster>(i).map(|(i, cl)| (i, Cluster(cl))),
-            // 0x1043A770 => sub_element(|i| Ok((i, SegmentElement::Chapters(Chapters {}))))(i),
-            // 0x1254C367 => sub_element(|i| Ok((i, SegmentElement::Tags(Tags {}))))(i),
-            // 0x1941A469 => sub_element(|i| Ok((i, SegmentElement::Attachments(Attachments {}))))(i),
-            0x1654AE6B => sub_element::<elements::Tracks>(i).map(|(i, tr)| (i, Tracks(tr))),
-            // 0x1C53BB6B => sub_element(|i| Ok((i, SegmentElement::Cues(Cues {}))))(i),
-            0xEC => {
-                let (i, size) = elem_size(i)?;
-                take(size)(i).map(|(i, _)| (i, Void(size)))
-            }
-            id => {
-                let (i, size) = opt(elem_size)(i)?;
-                match size {
-                    Some(sz) => {
-                        take(sz)(i).map(|(i, _)| (i, SegmentElement::Unknown(id, Some(sz))))
-                    }
-                    None => Ok((i, SegmentElement::Unknown(id, None))),
-                }
+    vid(input).and_then(|(i, id)| match id {
+        0x114D9B74 => master_element(i, at_eof).map(|(i, sh)| (i, SeekHead(sh))),
+        0x1549A966 => master_element(i, at_eof).map(|(i, info)| (i, Info(info))),
+        0x1F43B675 => master_element(i, at_eof).map(|(i, cl)| (i, Cluster(cl))),
+        0x1043A770 => master_element(i, at_eof).map(|(i, c)| (i, Chapters(c))),
+        0x1254C367 => master_element(i, at_eof).map(|(i, t)| (i, Tags(t))),
+        0x1941A469 => master_element(i, at_eof).map(|(i, a)| (i, Attachments(a))),
+        0x1654AE6B => master_element(i, at_eof).map(|(i, tr)| (i, Tracks(tr))),
+        0x1C53BB6B => master_element(i, at_eof).map(|(i, c)| (i, Cues(c))),
+        0xEC => {
+            let (i, size) = elem_size(i)?;
+            take(size)(i).map(|(i, _)| (i, Void(size)))
+        }
+        id => {
+            let (i, size) = opt(elem_size)(i)?;
+            match size {
+                Some(sz) => take(sz)(i).map(|(i, _)| (i, SegmentElement::Unknown(id, Some(sz)))),
+                None => Ok((i, SegmentElement::Unknown(id, None))),
             }
         }
     })
@@ -115,15 +152,107 @@ impl_ebml_master! {
     }
 }
 
-impl_ebml_master! {
-    // Element ID 0x1F43B675
-    #[derive(Debug, Clone, PartialEq)]
-    struct Cluster<'a> {
-        [0xE7] timestamp: (u64),
-        [0xA7] position: (Option<u64>),
-        [0xAB] prev_size: (Option<u64>),
-        [0xA3] simple_block: (Vec<&'a [u8]>) [0..],
-        [0xA0] block_group: (Vec<BlockGroup<'a>>) [0..],
+// Element ID 0x1F43B675
+/// A Cluster, with its SimpleBlocks and BlockGroups interleaved as stored.
+///
+/// The former `simple_block` and `block_group` fields lost that order, they
+/// are replaced by `blocks`. [Cluster::simple_blocks] and
+/// [Cluster::block_groups] give the same views as before.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cluster<'a> {
+    pub timestamp: u64,
+    pub position: Option<u64>,
+    pub prev_size: Option<u64>,
+    /// SimpleBlocks and BlockGroups, in their stored order, which is the decoding order
+    pub blocks: Vec<ClusterBlock<'a>>,
+}
+
+/// A SimpleBlock or a BlockGroup of a Cluster.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClusterBlock<'a> {
+    SimpleBlock(&'a [u8]),
+    BlockGroup(BlockGroup<'a>),
+}
+
+impl<'a> Cluster<'a> {
+    pub fn simple_blocks(&self) -> impl Iterator<Item = &'a [u8]> + '_ {
+        self.blocks.iter().filter_map(|b| match b {
+            ClusterBlock::SimpleBlock(data) => Some(*data),
+            ClusterBlock::BlockGroup(_) => None,
+        })
+    }
+
+    pub fn block_groups(&self) -> impl Iterator<Item = &BlockGroup<'a>> {
+        self.blocks.iter().filter_map(|b| match b {
+            ClusterBlock::SimpleBlock(_) => None,
+            ClusterBlock::BlockGroup(group) => Some(group),
+        })
+    }
+}
+
+// Parsed by hand, as the Blocks must keep their order
+impl<'a> EbmlParsable<'a> for Cluster<'a> {
+    fn has_crc() -> bool {
+        true
+    }
+
+    fn try_parse(mut input: &'a [u8]) -> Result<Self, ErrorKind> {
+        let kind = |e: nom::Err<Error>| match e {
+            nom::Err::Failure(e) | nom::Err::Error(e) => e.kind,
+            nom::Err::Incomplete(_) => ErrorKind::Nom(nom::error::ErrorKind::Complete),
+        };
+
+        let (mut timestamp, mut position, mut prev_size) = (None, None, None);
+        let mut blocks = Vec::new();
+        while !input.is_empty() {
+            let (_, id) = vid(input).map_err(kind)?;
+            input = match id {
+                0xE7 => {
+                    let (i, v) = ebml_element(0xE7)(input).map_err(kind)?;
+                    timestamp = Some(v);
+                    i
+                }
+                0xA7 => {
+                    let (i, v) = ebml_element(0xA7)(input).map_err(kind)?;
+                    position = Some(v);
+                    i
+                }
+                0xAB => {
+                    let (i, v) = ebml_element(0xAB)(input).map_err(kind)?;
+                    prev_size = Some(v);
+                    i
+                }
+                0xA3 => {
+                    let (i, data) = ebml_element(0xA3)(input).map_err(kind)?;
+                    blocks.push(ClusterBlock::SimpleBlock(data));
+                    i
+                }
+                0xA0 => {
+                    let (i, group) = ebml_element(0xA0)(input).map_err(kind)?;
+                    blocks.push(ClusterBlock::BlockGroup(group));
+                    i
+                }
+                _ => {
+                    let (i, id) = skip_element(input).map_err(kind)?;
+                    if id != 0xEC {
+                        match DEPRECATED.get(&id) {
+                            Some(name) => {
+                                log::warn!("Skipped deprecated Element '{name}' ({id:#0X})")
+                            }
+                            None => log::warn!("Skipped unknown Element {id:#0X}"),
+                        }
+                    }
+                    i
+                }
+            };
+        }
+
+        Ok(Cluster {
+            timestamp: get_required(timestamp, 0xE7)?,
+            position,
+            prev_size,
+            blocks,
+        })
     }
 }
 
@@ -135,7 +264,7 @@ impl_ebml_master! {
         // [0x75A1] block_additions: (Option<BlockAdditions>),
         [0x9B] block_duration: (Option<u64>),
         [0xFA] reference_priority: (u64) = 0,
-        [0xFB] reference_block: (Option<u64>),
+        [0xFB] reference_block: (Option<i64>),
         [0xA4] codec_state: (Option<Vec<u8>>),
         [0x75A2] discard_padding: (Option<i64>),
     }
@@ -149,7 +278,7 @@ pub struct Block {
     pub lacing: Lacing,
 }
 
-pub fn block(input: &[u8]) -> EbmlResult<Block> {
+pub fn block(input: &[u8]) -> EbmlResult<'_, Block> {
     map(
         tuple((vint, be_i16, map_opt(be_u8, block_flags))),
         |(track_number, timestamp, flags)| Block {
@@ -197,7 +326,7 @@ fn block_flags(data: u8) -> Option<BlockFlags> {
     })
 }
 
-pub fn simple_block(input: &[u8]) -> EbmlResult<SimpleBlock> {
+pub fn simple_block(input: &[u8]) -> EbmlResult<'_, SimpleBlock> {
     map(
         tuple((vint, be_i16, map_opt(be_u8, block_flags))),
         |(track_number, timestamp, flags)| SimpleBlock {
@@ -219,7 +348,7 @@ pub struct SimpleBlockFlags {
     pub discardable: bool,
 }
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Lacing {
     None,
     Xiph,
@@ -232,6 +361,99 @@ pub struct LacedData {
     pub frame_count: u8,
 }
 
+/// Splits the data of a Block or SimpleBlock into its frames.
+///
+/// `input` is the Block data following the Block header, starting with the
+/// lace count if the Block is laced.
+pub fn laced_frames<'a>(lacing: &Lacing, input: &'a [u8]) -> EbmlResult<'a, Vec<&'a [u8]>> {
+    let (mut i, sizes) = match lacing {
+        Lacing::None => return Ok((&input[input.len()..], vec![input])),
+        Lacing::Xiph => {
+            let (i, count) = complete(be_u8)(input)?;
+            xiph_lace_sizes(i, count as usize)?
+        }
+        Lacing::EBML => {
+            let (i, count) = complete(be_u8)(input)?;
+            ebml_lace_sizes(i, count as usize)?
+        }
+        Lacing::FixedSize => {
+            let (i, count) = complete(be_u8)(input)?;
+            let frames = count as usize + 1;
+            if i.len() % frames != 0 {
+                return ebml_err(0, ErrorKind::FixedLaceSizeMismatch);
+            }
+            (i, vec![i.len() / frames; count as usize])
+        }
+    };
+
+    let mut frames = Vec::with_capacity(sizes.len() + 1);
+    for size in sizes {
+        if size > i.len() {
+            return ebml_err(0, ErrorKind::LaceSizeTooLarge);
+        }
+
+        let (frame, rest) = i.split_at(size);
+        frames.push(frame);
+        i = rest;
+    }
+
+    // The size of the last frame is never stored, it takes the remaining data
+    frames.push(i);
+
+    Ok((&i[i.len()..], frames))
+}
+
+// Xiph lacing stores every size but the last one as a run of 255 bytes
+// followed by a byte lower than 255, all of them added together.
+fn xiph_lace_sizes(input: &[u8], count: usize) -> EbmlResult<'_, Vec<usize>> {
+    let mut i = input;
+    let mut sizes = Vec::with_capacity(count);
+
+    for _ in 0..count {
+        let mut size = 0;
+        loop {
+            let (rest, b) = complete(be_u8)(i)?;
+            i = rest;
+            size += b as usize;
+            if b != 0xFF {
+                break;
+            }
+        }
+        sizes.push(size);
+    }
+
+    Ok((i, sizes))
+}
+
+// EBML lacing stores the first size as a vint, and every following size
+// but the last one as a signed vint difference to the previous size.
+fn ebml_lace_sizes(input: &[u8], count: usize) -> EbmlResult<'_, Vec<usize>> {
+    let mut sizes = Vec::with_capacity(count);
+    if count == 0 {
+        return Ok((input, sizes));
+    }
+
+    let (mut i, first) = complete(vint)(input)?;
+    let mut size = first as i64;
+    sizes.push(first as usize);
+
+    for _ in 1..count {
+        let (rest, raw) = complete(vint)(i)?;
+        let len = i.offset(rest) as u32;
+        let bias = (1i64 << (7 * len - 1)) - 1;
+
+        size += raw as i64 - bias;
+        if size < 0 {
+            return ebml_err(0, ErrorKind::NegativeLaceSize);
+        }
+
+        sizes.push(size as usize);
+        i = rest;
+    }
+
+    Ok((i, sizes))
+}
+
 impl_ebml_master! {
     // Element ID 0x1654AE6B
     #[derive(Debug, Clone, PartialEq)]
@@ -242,17 +464,41 @@ impl_ebml_master! {
 
 impl Tracks {
     pub fn lookup(&self, track_number: u64) -> Option<usize> {
-        self.tracks
-            .iter()
-            .find(|t| t.track_number == track_number)
-            .map(|t| t.stream_index as usize)
+        self.find(track_number).map(|t| t.stream_index as usize)
+    }
+
+    pub fn find(&self, track_number: u64) -> Option<&TrackEntry> {
+        self.tracks.iter().find(|t| t.track_number == track_number)
     }
 }
 
-pub(crate) enum TrackType {
+/// Type of a TrackEntry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrackType {
     Video,
     Audio,
-    Other,
+    /// Mixed audio and video, as in a DV stream
+    Complex,
+    Logo,
+    Subtitle,
+    /// Menu buttons, such as the DVD ones
+    Buttons,
+    Control,
+    Metadata,
+    Other(u64),
+}
+
+impl TrackType {
+    /// Guesses the track type from the prefix of a Matroska codec ID.
+    pub fn from_codec_id(codec_id: &str) -> Option<TrackType> {
+        match codec_id.split_once('_')?.0 {
+            "V" => Some(Self::Video),
+            "A" => Some(Self::Audio),
+            "S" => Some(Self::Subtitle),
+            "B" => Some(Self::Buttons),
+            _ => None,
+        }
+    }
 }
 
 impl From<u64> for TrackType {
@@ -260,7 +506,13 @@ impl From<u64> for TrackType {
         match val {
             0x1 => Self::Video,
             0x2 => Self::Audio,
-            _ => Self::Other,
+            0x3 => Self::Complex,
+            0x10 => Self::Logo,
+            0x11 => Self::Subtitle,
+            0x12 => Self::Buttons,
+            0x20 => Self::Control,
+            0x21 => Self::Metadata,
+            val => Self::Other(val),
         }
     }
 }
@@ -270,7 +522,13 @@ impl From<TrackType> for u64 {
         match val {
             TrackType::Video => 0x1,
             TrackType::Audio => 0x2,
-            TrackType::Other => 0,
+            TrackType::Complex => 0x3,
+            TrackType::Logo => 0x10,
+            TrackType::Subtitle => 0x11,
+            TrackType::Buttons => 0x12,
+            TrackType::Control => 0x20,
+            TrackType::Metadata => 0x21,
+            TrackType::Other(val) => val,
         }
     }
 }
@@ -381,9 +639,8 @@ impl_ebml_master! {
     #[derive(Debug, Clone, PartialEq, Eq)]
     struct ContentCompression {
         [0x4254] algo: (u64) = 0,
-        [0x4255] settings: (Option<u64>),
+        [0x4255] settings: (Option<Vec<u8>>),
     }
-
 }
 
 impl_ebml_master! {
@@ -392,6 +649,15 @@ impl_ebml_master! {
     struct ContentEncryption {
         [0x47E1] enc_algo: (u64) = 0,
         [0x47E2] enc_key_id: (Option<Vec<u8>>),
+        [0x47E7] aes_settings: (Option<ContentEncAesSettings>),
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0x47E7
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct ContentEncAesSettings {
+        [0x47E8] cipher_mode: (u64),
     }
 }
 
@@ -483,6 +749,193 @@ impl_ebml_master! {
     }
 }
 
+impl_ebml_master! {
+    // Element ID 0x1C53BB6B
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Cues {
+        [0xBB] cue_points: (Vec<CuePoint>) [1..],
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0xBB
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct CuePoint {
+        [0xB3] time: (u64),
+        [0xB7] track_positions: (Vec<CueTrackPositions>) [1..],
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0xB7
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct CueTrackPositions {
+        [0xF7] track: (u64),
+        [0xF1] cluster_position: (u64),
+        [0xF0] relative_position: (Option<u64>),
+        [0xB2] duration: (Option<u64>),
+        [0x5378] block_number: (Option<u64>),
+        [0xEA] codec_state: (u64) = 0,
+        [0xDB] references: (Vec<CueReference>) [0..],
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0xDB
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct CueReference {
+        [0x96] ref_time: (u64),
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0x1043A770
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Chapters {
+        [0x45B9] editions: (Vec<EditionEntry>) [1..],
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0x45B9
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct EditionEntry {
+        [0x45BC] uid: (Option<u64>),
+        [0x45BD] flag_hidden: (u64) = 0,
+        [0x45DB] flag_default: (u64) = 0,
+        [0x45DD] flag_ordered: (u64) = 0,
+        [0xB6] atoms: (Vec<ChapterAtom>) [1..],
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0xB6
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct ChapterAtom {
+        [0x73C4] uid: (u64),
+        [0x5654] string_uid: (Option<String>),
+        [0x91] time_start: (u64),
+        [0x92] time_end: (Option<u64>),
+        [0x98] flag_hidden: (u64) = 0,
+        [0x4598] flag_enabled: (u64) = 1,
+        [0x6E67] segment_uuid: (Option<Uuid>),
+        [0x6EBC] segment_edition_uid: (Option<u64>),
+        [0x63C3] physical_equiv: (Option<u64>),
+        [0x8F] track: (Option<ChapterTrack>),
+        [0x80] displays: (Vec<ChapterDisplay>) [0..],
+        [0x6944] processes: (Vec<ChapProcess>) [0..],
+        [0xB6] atoms: (Vec<ChapterAtom>) [0..],
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0x8F
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct ChapterTrack {
+        [0x89] track_uids: (Vec<u64>) [1..],
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0x80
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct ChapterDisplay {
+        [0x85] string: (String),
+        [0x437C] languages: (Vec<String>) [0..],
+        [0x437D] languages_bcp47: (Vec<String>) [0..],
+        [0x437E] countries: (Vec<String>) [0..],
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0x6944
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct ChapProcess {
+        [0x6955] codec_id: (u64) = 0,
+        [0x450D] private: (Option<Vec<u8>>),
+        [0x6911] commands: (Vec<ChapProcessCommand>) [0..],
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0x6911
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct ChapProcessCommand {
+        [0x6922] time: (u64),
+        [0x6933] data: (Vec<u8>),
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0x1941A469
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Attachments {
+        [0x61A7] files: (Vec<AttachedFile>) [1..],
+    }
+}
+
+impl Attachments {
+    pub fn lookup(&self, uid: u64) -> Option<&AttachedFile> {
+        self.files.iter().find(|f| f.uid == uid)
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0x61A7
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct AttachedFile {
+        [0x467E] description: (Option<String>),
+        [0x466E] name: (String),
+        [0x4660] media_type: (String),
+        [0x465C] data: (Vec<u8>),
+        [0x46AE] uid: (u64),
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0x1254C367
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Tags {
+        [0x7373] tags: (Vec<Tag>) [1..],
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0x7373
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Tag {
+        [0x63C0] targets: (Targets),
+        [0x67C8] simple_tags: (Vec<SimpleTag>) [1..],
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0x63C0
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct Targets {
+        [0x68CA] target_type_value: (u64) = 50,
+        [0x63CA] target_type: (Option<String>),
+        [0x63C5] track_uids: (Vec<u64>) [0..],
+        [0x63C9] edition_uids: (Vec<u64>) [0..],
+        [0x63C4] chapter_uids: (Vec<u64>) [0..],
+        [0x63C6] attachment_uids: (Vec<u64>) [0..],
+    }
+}
+
+impl_ebml_master! {
+    // Element ID 0x67C8
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    struct SimpleTag {
+        [0x45A3] name: (String),
+        [0x447A] language: (String) = String::from("und"),
+        [0x447B] language_bcp47: (Option<String>),
+        [0x4484] default: (u64) = 1,
+        [0x4487] string: (Option<String>),
+        [0x4485] binary: (Option<Vec<u8>>),
+        [0x67C8] simple_tags: (Vec<SimpleTag>) [0..],
+    }
+}
+
 #[cfg(test)]
 #[allow(non_upper_case_globals)]
 mod tests {
@@ -494,6 +947,7 @@ mod tests {
 
     const mkv: &[u8] = include_bytes!("../assets/single_stream.mkv");
     const webm: &[u8] = include_bytes!("../assets/big-buck-bunny_trailer.webm");
+    const bbb: &[u8] = include_bytes!("../assets/bbb-vp9-opus.webm");
 
     #[test]
     fn block_flags() {
@@ -628,4 +1082,314 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn cues() {
+        // The SeekHead points the Cues at 23267 bytes after the Segment data start (55)
+        let (_, element) = segment_element(&bbb[55 + 23267..]).unwrap();
+
+        assert_eq!(
+            element,
+            SegmentElement::Cues(Cues {
+                cue_points: vec![CuePoint {
+                    time: 0,
+                    track_positions: vec![CueTrackPositions {
+                        track: 1,
+                        cluster_position: 473,
+                        relative_position: None,
+                        duration: None,
+                        block_number: None,
+                        codec_state: 0,
+                        references: vec![],
+                    }],
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn chapters() {
+        #[rustfmt::skip]
+        let data: &[u8] = &[
+            0x10, 0x43, 0xA7, 0x70, 0xF8, 0x45, 0xB9, 0xF5, 0x45, 0xBC, 0x82, 0x12,
+            0x34, 0x45, 0xDB, 0x81, 0x01, 0xB6, 0xCA, 0x73, 0xC4, 0x81, 0x01, 0x91,
+            0x81, 0x00, 0x92, 0x85, 0x01, 0x2A, 0x05, 0xF2, 0x00, 0x80, 0x92, 0x85,
+            0x85, 0x49, 0x6E, 0x74, 0x72, 0x6F, 0x43, 0x7C, 0x83, 0x65, 0x6E, 0x67,
+            0x43, 0x7E, 0x82, 0x75, 0x73, 0xB6, 0xA6, 0x73, 0xC4, 0x81, 0x02, 0x56,
+            0x54, 0x83, 0x73, 0x75, 0x62, 0x91, 0x84, 0x3B, 0x9A, 0xCA, 0x00, 0x98,
+            0x81, 0x01, 0x80, 0x91, 0x85, 0x84, 0x54, 0x65, 0x69, 0x6C, 0x43, 0x7C,
+            0x83, 0x67, 0x65, 0x72, 0x43, 0x7D, 0x82, 0x64, 0x65, 0xB6, 0x9E, 0x73,
+            0xC4, 0x81, 0x03, 0x91, 0x85, 0x01, 0x2A, 0x05, 0xF2, 0x00, 0x69, 0x44,
+            0x90, 0x69, 0x55, 0x81, 0x01, 0x69, 0x11, 0x89, 0x69, 0x22, 0x81, 0x01,
+            0x69, 0x33, 0x82, 0x01, 0x02,
+        ];
+
+        let atom = |uid, time_start| ChapterAtom {
+            uid,
+            string_uid: None,
+            time_start,
+            time_end: None,
+            flag_hidden: 0,
+            flag_enabled: 1,
+            segment_uuid: None,
+            segment_edition_uid: None,
+            physical_equiv: None,
+            track: None,
+            displays: vec![],
+            processes: vec![],
+            atoms: vec![],
+        };
+
+        let (i, element) = segment_element(data).unwrap();
+        assert!(i.is_empty());
+        assert_eq!(
+            element,
+            SegmentElement::Chapters(Chapters {
+                editions: vec![EditionEntry {
+                    uid: Some(0x1234),
+                    flag_hidden: 0,
+                    flag_default: 1,
+                    flag_ordered: 0,
+                    atoms: vec![
+                        ChapterAtom {
+                            time_end: Some(5_000_000_000),
+                            displays: vec![ChapterDisplay {
+                                string: String::from("Intro"),
+                                languages: vec![String::from("eng")],
+                                languages_bcp47: vec![],
+                                countries: vec![String::from("us")],
+                            }],
+                            atoms: vec![ChapterAtom {
+                                string_uid: Some(String::from("sub")),
+                                flag_hidden: 1,
+                                displays: vec![ChapterDisplay {
+                                    string: String::from("Teil"),
+                                    languages: vec![String::from("ger")],
+                                    languages_bcp47: vec![String::from("de")],
+                                    countries: vec![],
+                                }],
+                                ..atom(2, 1_000_000_000)
+                            }],
+                            ..atom(1, 0)
+                        },
+                        ChapterAtom {
+                            processes: vec![ChapProcess {
+                                codec_id: 1,
+                                private: None,
+                                commands: vec![ChapProcessCommand {
+                                    time: 1,
+                                    data: vec![0x01, 0x02],
+                                }],
+                            }],
+                            ..atom(3, 5_000_000_000)
+                        },
+                    ],
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn attachments() {
+        #[rustfmt::skip]
+        let data: &[u8] = &[
+            0x19, 0x41, 0xA4, 0x69, 0xD3, 0x61, 0xA7, 0xAC, 0x46, 0x7E, 0x85, 0x43,
+            0x6F, 0x76, 0x65, 0x72, 0x46, 0x6E, 0x89, 0x63, 0x6F, 0x76, 0x65, 0x72,
+            0x2E, 0x6A, 0x70, 0x67, 0x46, 0x60, 0x8A, 0x69, 0x6D, 0x61, 0x67, 0x65,
+            0x2F, 0x6A, 0x70, 0x65, 0x67, 0x46, 0x5C, 0x84, 0xFF, 0xD8, 0xFF, 0xE0,
+            0x46, 0xAE, 0x81, 0x2A, 0x61, 0xA7, 0xA1, 0x46, 0x6E, 0x88, 0x66, 0x6F,
+            0x6E, 0x74, 0x2E, 0x74, 0x74, 0x66, 0x46, 0x60, 0x88, 0x66, 0x6F, 0x6E,
+            0x74, 0x2F, 0x74, 0x74, 0x66, 0x46, 0x5C, 0x84, 0x00, 0x01, 0x00, 0x00,
+            0x46, 0xAE, 0x81, 0x07,
+        ];
+
+        let (i, element) = segment_element(data).unwrap();
+        assert!(i.is_empty());
+
+        let attachments = match element {
+            SegmentElement::Attachments(a) => a,
+            e => panic!("unexpected element: {e:?}"),
+        };
+
+        assert_eq!(
+            attachments.files,
+            vec![
+                AttachedFile {
+                    description: Some(String::from("Cover")),
+                    name: String::from("cover.jpg"),
+                    media_type: String::from("image/jpeg"),
+                    data: vec![0xFF, 0xD8, 0xFF, 0xE0],
+                    uid: 42,
+                },
+                AttachedFile {
+                    description: None,
+                    name: String::from("font.ttf"),
+                    media_type: String::from("font/ttf"),
+                    data: vec![0x00, 0x01, 0x00, 0x00],
+                    uid: 7,
+                },
+            ]
+        );
+
+        assert_eq!(
+            attachments.lookup(7).map(|f| f.name.as_str()),
+            Some("font.ttf")
+        );
+        assert_eq!(attachments.lookup(8), None);
+    }
+
+    #[test]
+    fn tags() {
+        // assets/matroska_test_w1_1/test1-tag.xml
+        #[rustfmt::skip]
+        let data: &[u8] = &[
+            0x12, 0x54, 0xC3, 0x67, 0x40, 0xA0, 0x73, 0x73, 0x40, 0x9C, 0x63, 0xC0,
+            0x84, 0x68, 0xCA, 0x81, 0x32, 0x67, 0xC8, 0xA2, 0x45, 0xA3, 0x85, 0x54,
+            0x49, 0x54, 0x4C, 0x45, 0x44, 0x87, 0x97, 0x42, 0x69, 0x67, 0x20, 0x42,
+            0x75, 0x63, 0x6B, 0x20, 0x42, 0x75, 0x6E, 0x6E, 0x79, 0x20, 0x2D, 0x20,
+            0x74, 0x65, 0x73, 0x74, 0x20, 0x31, 0x67, 0xC8, 0x97, 0x45, 0xA3, 0x8D,
+            0x44, 0x41, 0x54, 0x45, 0x5F, 0x52, 0x45, 0x4C, 0x45, 0x41, 0x53, 0x45,
+            0x44, 0x44, 0x87, 0x84, 0x32, 0x30, 0x31, 0x30, 0x67, 0xC8, 0xD3, 0x45,
+            0xA3, 0x87, 0x43, 0x4F, 0x4D, 0x4D, 0x45, 0x4E, 0x54, 0x44, 0x87, 0xC6,
+            0x4D, 0x61, 0x74, 0x72, 0x6F, 0x73, 0x6B, 0x61, 0x20, 0x56, 0x61, 0x6C,
+            0x69, 0x64, 0x61, 0x74, 0x69, 0x6F, 0x6E, 0x20, 0x46, 0x69, 0x6C, 0x65,
+            0x31, 0x2C, 0x20, 0x62, 0x61, 0x73, 0x69, 0x63, 0x20, 0x4D, 0x50, 0x45,
+            0x47, 0x34, 0x2E, 0x32, 0x20, 0x61, 0x6E, 0x64, 0x20, 0x4D, 0x50, 0x33,
+            0x20, 0x77, 0x69, 0x74, 0x68, 0x20, 0x6F, 0x6E, 0x6C, 0x79, 0x20, 0x53,
+            0x69, 0x6D, 0x70, 0x6C, 0x65, 0x42, 0x6C, 0x6F, 0x63, 0x6B,
+        ];
+
+        let simple_tag = |name: &str, string: &str| SimpleTag {
+            name: String::from(name),
+            language: String::from("und"),
+            language_bcp47: None,
+            default: 1,
+            string: Some(String::from(string)),
+            binary: None,
+            simple_tags: vec![],
+        };
+
+        let (i, element) = segment_element(data).unwrap();
+        assert!(i.is_empty());
+        assert_eq!(
+            element,
+            SegmentElement::Tags(Tags {
+                tags: vec![Tag {
+                    targets: Targets {
+                        target_type_value: 50,
+                        target_type: None,
+                        track_uids: vec![],
+                        edition_uids: vec![],
+                        chapter_uids: vec![],
+                        attachment_uids: vec![],
+                    },
+                    simple_tags: vec![
+                        simple_tag("TITLE", "Big Buck Bunny - test 1"),
+                        simple_tag("DATE_RELEASED", "2010"),
+                        simple_tag(
+                            "COMMENT",
+                            "Matroska Validation File1, basic MPEG4.2 and MP3 with only SimpleBlock"
+                        ),
+                    ],
+                }],
+            })
+        );
+    }
+
+    #[test]
+    fn lacing() {
+        let frames = [vec![1u8; 300], vec![2u8; 10], vec![3u8; 5]];
+        let payload = frames.concat();
+
+        let laced = [
+            (
+                Lacing::Xiph,
+                [&[0x02, 0xFF, 0x2D, 0x0A][..], &payload].concat(),
+            ),
+            (
+                Lacing::EBML,
+                [&[0x02, 0x41, 0x2C, 0x5E, 0xDD][..], &payload].concat(),
+            ),
+        ];
+
+        for (lacing, data) in laced {
+            let (i, o) = laced_frames(&lacing, &data).unwrap();
+            assert!(i.is_empty());
+            assert_eq!(o, frames, "{lacing:?}");
+        }
+
+        let data = [&[0x02][..], &[4u8; 9][..]].concat();
+        let (_, o) = laced_frames(&Lacing::FixedSize, &data).unwrap();
+        assert_eq!(o, vec![&[4u8; 3][..]; 3]);
+
+        let (_, o) = laced_frames(&Lacing::None, &payload).unwrap();
+        assert_eq!(o, vec![&payload[..]]);
+    }
+
+    #[test]
+    fn lacing_errors() {
+        let errors = [
+            (
+                Lacing::Xiph,
+                &[0x01, 0xFF, 0x10, 0x00][..],
+                ErrorKind::LaceSizeTooLarge,
+            ),
+            // 0x5E 0xDD is a difference of -290 from the first size of 10
+            (
+                Lacing::EBML,
+                &[0x02, 0x8A, 0x5E, 0xDD, 0x00][..],
+                ErrorKind::NegativeLaceSize,
+            ),
+            (
+                Lacing::FixedSize,
+                &[0x01, 0x00, 0x00, 0x00][..],
+                ErrorKind::FixedLaceSizeMismatch,
+            ),
+        ];
+
+        for (lacing, data, kind) in errors {
+            match laced_frames(&lacing, data) {
+                Err(nom::Err::Error(e)) => assert_eq!(e.kind, kind),
+                res => panic!("unexpected result for {lacing:?}: {res:?}"),
+            }
+        }
+    }
+
+    #[test]
+    fn unknown_size_cluster() {
+        #[rustfmt::skip]
+        let clusters = [
+            0x1F, 0x43, 0xB6, 0x75, 0xFF,
+                0xE7, 0x81, 0x00,
+                0xA3, 0x85, 0x81, 0x00, 0x00, 0x80, 0xAA,
+                0xA3, 0x85, 0x81, 0x00, 0x10, 0x80, 0xBB,
+            0x1F, 0x43, 0xB6, 0x75, 0x01, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+                0xE7, 0x81, 0x20,
+                0xA3, 0x85, 0x81, 0x00, 0x00, 0x80, 0xCC,
+        ];
+
+        let (i, first) = segment_element(&clusters).unwrap();
+        match first {
+            SegmentElement::Cluster(c) => {
+                assert_eq!(c.timestamp, 0);
+                assert_eq!(c.simple_blocks().count(), 2);
+            }
+            el => panic!("expected a Cluster, got {el:?}"),
+        }
+
+        // The last Cluster only ends with the input once it is known to be complete
+        assert!(matches!(segment_element(i), Err(nom::Err::Incomplete(_))));
+        let (i, last) = segment_element_eof(i).unwrap();
+        assert!(i.is_empty());
+        match last {
+            SegmentElement::Cluster(c) => {
+                assert_eq!(c.timestamp, 0x20);
+                assert_eq!(
+                    c.blocks,
+                    vec![ClusterBlock::SimpleBlock(&[0x81, 0x00, 0x00, 0x80, 0xCC])]
+                );
+            }
+            el => panic!("expected a Cluster, got {el:?}"),
+        }
+    }
 }
@@ -1,6 +1,8 @@
+pub mod codec;
 pub mod demuxer;
 pub mod ebml;
 pub mod elements;
+pub mod language;
 pub mod muxer;
 pub mod permutation;
 pub mod serializer;
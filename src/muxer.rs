@@ -1,37 +1,160 @@
-use std::io::Write;
+use std::collections::HashMap;
+use std::io::{self, Seek as _, SeekFrom, Write};
 use std::sync::Arc;
 
 use cookie_factory::GenError;
 use log::error;
+use uuid::Uuid;
 
-use av_data::{packet::Packet, params::MediaKind, value::Value};
+use av_data::{
+    packet::Packet,
+    params::{AudioInfo, MediaKind},
+    pixel::{
+        ChromaLocation, ColorModel, Formaton, TrichromaticEncodingSystem, YUVRange, YUVSystem,
+    },
+    rational::Rational64,
+    value::Value,
+};
 use av_format::{common::GlobalInfo, error::*, muxer::*, stream::Stream};
 
 use crate::{
-    ebml::EbmlHeader,
+    codec::{
+        self, channels::channel_mask, config::AudioSpecificConfig, BitmapInfoHeader, WaveFormatEx,
+    },
+    ebml::{elem_size, vid, EbmlHeader, CRC},
     elements::{
-        Audio, Cluster, Colour, Info, Lacing, Seek, SeekHead, SimpleBlock, TrackEntry, TrackType,
-        Tracks, Video,
+        Audio, BlockGroup, Cluster, ClusterBlock, Colour, CuePoint, CueTrackPositions, Cues, Info,
+        Lacing, Seek, SeekHead, SimpleBlock, TrackEntry, TrackType, Tracks, Video,
     },
     serializer::{
-        cookie_utils::tuple,
-        ebml::{gen_ebml_header, EbmlSize},
+        cookie_utils::{gen_slice, tuple},
+        ebml::{gen_ebml_header, gen_vint_l, gen_void, vint_size, EbmlSize},
         elements::{
-            gen_cluster, gen_info, gen_seek_head, gen_segment_header_unknown_size,
-            gen_simple_block_header, gen_tracks,
+            block_size, gen_cluster, gen_cues, gen_info, gen_laced_frames, gen_seek_head,
+            gen_seek_head_reserved, gen_segment_header, gen_simple_block_header, gen_tracks,
+            lacing_size,
         },
     },
 };
 
+// Space taken by the SeekHead and its Void padding, enough for 4 entries
+const SEEK_HEAD_SIZE: usize = 96;
+
+// Most audio frames laced in a single Block
+const MAX_LACED_FRAMES: usize = 8;
+
+/// Matroska and WebM muxer.
+///
+/// A Cluster is started at every video keyframe, and whenever the current one
+/// would exceed its maximum duration or size, or the range of the relative
+/// Block timestamps. The options set through [Muxer::set_option] are:
+///
+/// - `title` (str): title of the Segment
+/// - `writing_app` and `muxing_app` (str): names of the applications writing
+///   the file, `rust-av` by default
+/// - `segment_uid` (str): UUID of the Segment
+/// - `timestamp_scale` (u64): duration of a Segment tick, in nanoseconds
+/// - `cluster_max_duration` (u64): maximum time span of a Cluster, in milliseconds
+/// - `cluster_max_size` (u64): maximum size of the Blocks of a Cluster, in bytes
+/// - `cues` (bool): write the Cues, true by default
+/// - `cues_placement` (str): `end` or `front`; the Cues are written at the end
+///   of the Segment, or by [MkvMuxer::finalize] into the header Void if they fit
+/// - `crc32` (bool): start the top level master Elements with a CRC-32 Element
+/// - `header_void_size` (u64): size of a Void written after the Tracks, in bytes
+/// - `doc_type_version` (u64): DocTypeVersion of the EBML header
+///
+/// Unknown options and values of the wrong type are rejected.
 #[derive(Debug, Clone, PartialEq)]
 pub struct MkvMuxer {
     header: EbmlHeader,
     seek_head: SeekHead,
     info: Option<Info>,
     tracks: Option<Tracks>,
-    blocks: Vec<Vec<u8>>,
+    blocks: Vec<PendingBlock>,
     blocks_len: usize,
     timestamp: Option<u64>,
+    /// Position of the Segment data, which the SeekHead and Cues positions are relative to
+    segment_offset: u64,
+    cue_points: Vec<CuePoint>,
+    /// Only index the keyframes of the video tracks, if there are any
+    cues_video_only: bool,
+    /// End of the last packet, in Segment ticks
+    end_timestamp: Option<u64>,
+    /// Timebase of the packets of each track
+    timebases: HashMap<u64, Rational64>,
+    /// Longest time span of a Cluster, in milliseconds
+    cluster_max_duration: u64,
+    /// Largest size of the Blocks of a Cluster, in bytes
+    cluster_max_size: usize,
+    /// Lace the consecutive frames of the audio tracks
+    lacing: bool,
+    /// Timestamp of the last packet of each track, in Segment ticks
+    last_timestamps: HashMap<u64, u64>,
+    /// Write the Cues
+    cues: bool,
+    /// Write the Cues into the header Void instead of the end of the Segment
+    cues_at_front: bool,
+    /// Start the top level master Elements with a CRC-32 Element
+    crc32: bool,
+    /// Size of the Void written after the Tracks
+    header_void_size: usize,
+    /// Position of the header Void, relative to the Segment data
+    header_void_position: u64,
+    /// Cues left for finalize to write into the header Void
+    front_cues: Option<Vec<u8>>,
+}
+
+/// Track properties that a [Stream] cannot describe, attached to it as its
+/// `user_private` data for [stream_to_track].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrackProperties {
+    /// BCP 47 language tag, or ISO 639-2 code
+    pub language: Option<String>,
+    pub name: Option<String>,
+    /// Whether players pick the track when the user has no preference, true by default
+    pub default: bool,
+    /// Whether players pick the track even against the user preferences,
+    /// e.g. for subtitles translating foreign dialogue
+    pub forced: bool,
+    /// Width of a pixel over its height
+    pub sample_aspect_ratio: Option<Rational64>,
+    /// Frames per second, which gives the DefaultDuration
+    pub frame_rate: Option<Rational64>,
+}
+
+impl Default for TrackProperties {
+    fn default() -> Self {
+        TrackProperties {
+            language: None,
+            name: None,
+            default: true,
+            forced: false,
+            sample_aspect_ratio: None,
+            frame_rate: None,
+        }
+    }
+}
+
+// A Block waiting for its Cluster to be written, with its times in Segment ticks
+#[derive(Debug, Clone, PartialEq)]
+struct PendingBlock {
+    track_number: u64,
+    timestamp: u64,
+    keyframe: bool,
+    duration: Option<u64>,
+    frames: Vec<Vec<u8>>,
+    // BlockGroup elements, for the Blocks a SimpleBlock cannot describe
+    block_duration: Option<u64>,
+    reference_block: Option<i64>,
+    discard_padding: Option<i64>,
+}
+
+impl PendingBlock {
+    fn is_group(&self) -> bool {
+        self.block_duration.is_some()
+            || self.reference_block.is_some()
+            || self.discard_padding.is_some()
+    }
 }
 
 impl MkvMuxer {
@@ -54,28 +177,35 @@ impl MkvMuxer {
             blocks: Vec::new(),
             blocks_len: 0,
             timestamp: None,
+            segment_offset: 0,
+            cue_points: Vec::new(),
+            cues_video_only: true,
+            end_timestamp: None,
+            timebases: HashMap::new(),
+            cluster_max_duration: 5000,
+            cluster_max_size: 5242880,
+            lacing: false,
+            last_timestamps: HashMap::new(),
+            cues: true,
+            cues_at_front: false,
+            crc32: false,
+            header_void_size: 0,
+            header_void_position: 0,
+            front_cues: None,
         }
     }
 
     pub fn webm() -> MkvMuxer {
+        let matroska = Self::matroska();
+
         MkvMuxer {
             header: EbmlHeader {
-                version: 1,
-                read_version: 1,
-                max_id_length: 4,
-                max_size_length: 8,
                 doc_type: String::from("webm"),
                 doc_type_version: 1,
                 doc_type_read_version: 1,
+                ..matroska.header
             },
-            seek_head: SeekHead {
-                positions: Vec::new(),
-            },
-            info: None,
-            tracks: None,
-            blocks: Vec::new(),
-            blocks_len: 0,
-            timestamp: None,
+            ..matroska
         }
     }
 
@@ -110,7 +240,8 @@ impl MkvMuxer {
         Ok(())
     }
 
-    pub fn write_segment_header(&mut self, buf: &mut Vec<u8>, _size: usize) -> Result<()> {
+    /// Writes the Segment header, with an unknown size if `size` is `None`.
+    pub fn write_segment_header(&mut self, buf: &mut Vec<u8>, size: Option<u64>) -> Result<()> {
         let mut origin = (buf).as_ptr() as usize;
 
         let mut needed = 0usize;
@@ -122,7 +253,7 @@ impl MkvMuxer {
                 origin = (buf).as_ptr() as usize;
             }
 
-            match gen_segment_header_unknown_size()((buf, 0)) {
+            match gen_segment_header(size)((buf, 0)) {
                 Err(GenError::BufferTooSmall(sz)) => {
                     needed = sz;
                 }
@@ -235,6 +366,242 @@ impl MkvMuxer {
     }
 }
 
+impl MkvMuxer {
+    /// Goes back to complete the header once the trailer is written.
+    ///
+    /// The Segment size is patched, the SeekHead is rewritten with the
+    /// position of the Cues and the Info gets the Duration of the packets
+    /// written. Cues placed at the front are written into the header Void.
+    /// The [Muxer] trait cannot require a seekable output, so this is a
+    /// separate step.
+    ///
+    /// Without it the file is still complete and readable, but the Segment
+    /// keeps an unknown size, the Duration is the one of the [GlobalInfo] if
+    /// any, and the Cues can only be found by reading through the Clusters.
+    pub fn finalize<W: Write + io::Seek>(&mut self, out: &mut Writer<W>) -> Result<()> {
+        let end = out.position() as u64;
+        let segment_header = serialize(|i| gen_segment_header(Some(end - self.segment_offset))(i))?;
+        let seek_head = self.seek_head_element()?;
+
+        out.seek(SeekFrom::Start(
+            self.segment_offset - segment_header.len() as u64,
+        ))?;
+        out.write_all(&segment_header)?;
+        out.write_all(&seek_head)?;
+
+        if let Some(info) = self.info.as_mut() {
+            if let Some(end_timestamp) = self.end_timestamp {
+                info.duration = Some(end_timestamp as f64);
+            }
+            // Same size as the Info and Void written by write_header
+            out.write_all(&self.info_element()?)?;
+        }
+
+        if let Some(cues) = self.front_cues.take() {
+            let void = self.header_void_size - cues.len();
+            out.seek(SeekFrom::Start(
+                self.segment_offset + self.header_void_position,
+            ))?;
+            out.write_all(&cues)?;
+            out.write_all(&serialize(|i| gen_void(void)(i))?)?;
+        }
+
+        out.seek(SeekFrom::Start(end))?;
+
+        Ok(())
+    }
+
+    /// Whether only the keyframes of the video tracks are cued, when there
+    /// are any. This is the default, like mkvmerge.
+    pub fn set_cues_video_only(&mut self, video_only: bool) {
+        self.cues_video_only = video_only;
+    }
+
+    /// Whether consecutive audio frames are laced, for the tracks with a
+    /// DefaultDuration and the FlagLacing set. This is off by default.
+    pub fn set_lacing(&mut self, lacing: bool) {
+        self.lacing = lacing;
+    }
+
+    fn write_cluster<W: Write>(&mut self, out: &mut Writer<W>) -> Result<()> {
+        let Some(timestamp) = self.timestamp.take() else {
+            return Ok(());
+        };
+        let position = out.position() as u64 - self.segment_offset;
+        let blocks = std::mem::take(&mut self.blocks);
+        self.blocks_len = 0;
+
+        let mut block_data = Vec::new();
+        for block in blocks.iter() {
+            // write_packet keeps the Cluster within the range of the relative timestamps
+            let frames = block.frames.iter().map(|f| &f[..]).collect::<Vec<_>>();
+            let lacing = block_lacing(&frames);
+            let header = SimpleBlock {
+                track_number: block.track_number,
+                timestamp: (block.timestamp as i64 - timestamp as i64) as i16,
+                // A Block has no keyframe flag, its lack of references tells instead
+                keyframe: block.keyframe && !block.is_group(),
+                invisible: false,
+                lacing,
+                discardable: false,
+            };
+            let mut data = serialize(|i| gen_simple_block_header(&header)(i))?;
+            data.extend(serialize(|i| gen_laced_frames(i, lacing, &frames))?);
+            block_data.push(data);
+        }
+
+        // The Blocks are written in the order of their packets
+        let cluster = Cluster {
+            timestamp,
+            position: None,
+            prev_size: None,
+            blocks: blocks
+                .iter()
+                .zip(block_data.iter())
+                .map(|(block, data)| {
+                    if block.is_group() {
+                        ClusterBlock::BlockGroup(BlockGroup {
+                            block: data,
+                            block_duration: block.block_duration,
+                            reference_priority: 0,
+                            reference_block: block.reference_block,
+                            codec_state: None,
+                            discard_padding: block.discard_padding,
+                        })
+                    } else {
+                        ClusterBlock::SimpleBlock(data)
+                    }
+                })
+                .collect(),
+        };
+
+        // Blocks are located from the start of the Cluster data
+        let mut relative_position = timestamp.size(0xE7) + if self.crc32 { 6 } else { 0 };
+        // Like mkvmerge, only the first keyframe of the other tracks is cued in each Cluster
+        let mut cued_tracks = Vec::new();
+        for (block, cluster_block) in blocks.iter().zip(cluster.blocks.iter()) {
+            let block_position = relative_position;
+            relative_position += block_size(cluster_block);
+
+            if block.keyframe && self.is_cued(block.track_number) {
+                if !self.is_video(block.track_number) {
+                    if cued_tracks.contains(&block.track_number) {
+                        continue;
+                    }
+                    cued_tracks.push(block.track_number);
+                }
+
+                let track_positions = CueTrackPositions {
+                    track: block.track_number,
+                    cluster_position: position,
+                    relative_position: Some(block_position as u64),
+                    duration: block.duration,
+                    block_number: None,
+                    codec_state: 0,
+                    references: Vec::new(),
+                };
+                match self.cue_points.last_mut() {
+                    Some(point) if point.time == block.timestamp => {
+                        point.track_positions.push(track_positions)
+                    }
+                    _ => self.cue_points.push(CuePoint {
+                        time: block.timestamp,
+                        track_positions: vec![track_positions],
+                    }),
+                }
+            }
+        }
+
+        let cluster = serialize(|i| gen_cluster(&cluster)(i))?;
+        out.write_all(&self.crc32_element(cluster)?)?;
+
+        Ok(())
+    }
+
+    // The SeekHead, padded with a Void to SEEK_HEAD_SIZE bytes
+    fn seek_head_element(&self) -> Result<Vec<u8>> {
+        if !self.crc32 {
+            return serialize(|i| gen_seek_head_reserved(&self.seek_head, SEEK_HEAD_SIZE)(i));
+        }
+
+        let seek_head = serialize(|i| gen_seek_head(&self.seek_head)(i))?;
+        let mut element = with_crc32(&seek_head, 0)?;
+        // A Void takes at least 2 bytes, widen the size instead
+        if SEEK_HEAD_SIZE.checked_sub(element.len()) == Some(1) {
+            element = with_crc32(&seek_head, 1)?;
+        }
+        let void = SEEK_HEAD_SIZE
+            .checked_sub(element.len())
+            .ok_or(Error::InvalidData)?;
+        element.extend(serialize(|i| gen_void(void)(i))?);
+
+        Ok(element)
+    }
+
+    fn info_element(&mut self) -> Result<Vec<u8>> {
+        let mut info = Vec::new();
+        self.write_info(&mut info)?;
+        self.crc32_element(info)
+    }
+
+    fn crc32_element(&self, element: Vec<u8>) -> Result<Vec<u8>> {
+        if self.crc32 && !element.is_empty() {
+            with_crc32(&element, 0)
+        } else {
+            Ok(element)
+        }
+    }
+
+    fn is_cued(&self, track_number: u64) -> bool {
+        let Some(tracks) = self.tracks.as_ref() else {
+            return false;
+        };
+
+        self.cues
+            && (!self.cues_video_only
+                || !tracks.tracks.iter().any(is_video)
+                || self.is_video(track_number))
+    }
+
+    fn is_video(&self, track_number: u64) -> bool {
+        self.tracks
+            .as_ref()
+            .and_then(|t| t.find(track_number))
+            .is_some_and(is_video)
+    }
+
+    // DefaultDuration of a track, in Segment ticks
+    fn default_duration(&self, track_number: u64) -> Option<u64> {
+        let track = self.tracks.as_ref()?.find(track_number)?;
+        let timestamp_scale = self.info.as_ref().map_or(1000000, |i| i.timestamp_scale);
+
+        track
+            .default_duration
+            .map(|d| (d as f64 / timestamp_scale as f64).round() as u64)
+            .filter(|&d| d > 0)
+    }
+
+    // Laced frames get their timestamps from the DefaultDuration of their track
+    fn is_laced(&self, track_number: u64) -> bool {
+        self.lacing
+            && self
+                .tracks
+                .as_ref()
+                .and_then(|t| t.find(track_number))
+                .is_some_and(|t| t.flag_lacing != 0 && t.track_type == u64::from(TrackType::Audio))
+    }
+
+    fn info_mut(&mut self) -> &mut Info {
+        self.info.get_or_insert_with(default_info)
+    }
+
+    // Converts a time in `timebase` units to Segment ticks
+    fn ticks(&self, t: i64, timebase: Rational64) -> i64 {
+        let timestamp_scale = self.info.as_ref().map_or(1000000, |i| i.timestamp_scale);
+        rescale(t, timebase, timestamp_scale)
+    }
+}
+
 impl Muxer for MkvMuxer {
     fn configure(&mut self) -> Result<()> {
         Ok(())
@@ -245,190 +612,175 @@ impl Muxer for MkvMuxer {
         let mut ebml_header = Vec::new();
         self.write_ebml_header(&mut ebml_header)?;
         let mut segment_header = Vec::new();
-        //127 corresponds to unknown size
-        self.write_segment_header(&mut segment_header, 127)?;
+        // The size is patched by finalize
+        self.write_segment_header(&mut segment_header, None)?;
 
         buf.extend_from_slice(&ebml_header);
         buf.extend_from_slice(&segment_header);
+        self.segment_offset = (out.position() + buf.len()) as u64;
 
-        let mut info = Vec::new();
-        self.write_info(&mut info)?;
+        let mut info = self.info_element()?;
+        // Leave room for a Duration, which finalize writes over the Void
+        if let Some(info_element) = self.info.as_mut().filter(|i| i.duration.is_none()) {
+            info_element.duration = Some(0.0);
+            let with_duration = self.info_element();
+            if let Some(info_element) = self.info.as_mut() {
+                info_element.duration = None;
+            }
+            let void = with_duration?.len() - info.len();
+            info.extend(serialize(|i| gen_void(void)(i))?);
+        }
         let mut tracks = Vec::new();
         self.write_tracks(&mut tracks)?;
+        let tracks = self.crc32_element(tracks)?;
+        // Room left for the Cues, or for the user to edit the header later
+        let void = serialize(|i| gen_void(self.header_void_size)(i))?;
+        self.header_void_position = (SEEK_HEAD_SIZE + info.len() + tracks.len()) as u64;
 
-        let info_seek = Seek {
-            id: [0x15, 0x49, 0xA9, 0x66],
-            position: 0,
-        };
-        let tracks_seek = Seek {
-            id: [0x16, 0x54, 0xAE, 0x6B],
-            position: 0,
-        };
-        let cluster_seek = Seek {
-            id: [0x1F, 0x43, 0xB6, 0x75],
-            position: 0,
-        };
-        self.seek_head.positions.push(info_seek);
-        self.seek_head.positions.push(tracks_seek);
-        self.seek_head.positions.push(cluster_seek);
-
-        self.seek_head.positions[0].position = self.seek_head.size(0x114D9B74) as u64;
-        self.seek_head.positions[1].position =
-            (self.seek_head.size(0x114D9B74) + info.size(0x1549A966)) as u64;
-        self.seek_head.positions[2].position = (self.seek_head.size(0x114D9B74)
-            + info.size(0x1549A966)
-            + tracks.size(0x1654AE6B)) as u64;
-
-        let mut seek_head = Vec::new();
-        self.write_seek_head(&mut seek_head)?;
+        // The SeekHead is completed by finalize, once the Cues are written
+        self.seek_head.positions = vec![
+            Seek {
+                id: [0x15, 0x49, 0xA9, 0x66],
+                position: SEEK_HEAD_SIZE as u64,
+            },
+            Seek {
+                id: [0x16, 0x54, 0xAE, 0x6B],
+                position: (SEEK_HEAD_SIZE + info.len()) as u64,
+            },
+        ];
+        let seek_head = self.seek_head_element()?;
 
         buf.extend_from_slice(&seek_head);
         buf.extend_from_slice(&info);
         buf.extend_from_slice(&tracks);
+        buf.extend_from_slice(&void);
 
-        out.write_all(&buf).unwrap();
+        out.write_all(&buf)?;
 
         Ok(())
     }
 
     fn write_packet<W: Write>(&mut self, out: &mut Writer<W>, pkt: Arc<Packet>) -> Result<()> {
-        let mut v = Vec::with_capacity(16);
-
-        let s = SimpleBlock {
-            track_number: pkt.stream_index as u64 + 1,
-            timestamp: pkt.t.pts.or(pkt.t.dts).unwrap_or(0) as i16,
-            keyframe: pkt.is_key,
-            invisible: false,
-            lacing: Lacing::None,
-            discardable: false,
-        };
+        let track_number = pkt.stream_index as u64 + 1;
+        let timebase = self
+            .timebases
+            .get(&track_number)
+            .copied()
+            .unwrap_or_else(|| Rational64::new(1, 1000));
+        let timestamp = self
+            .ticks(pkt.t.pts.or(pkt.t.dts).unwrap_or(0), timebase)
+            .max(0) as u64;
+        let duration = pkt
+            .t
+            .duration
+            .map(|d| self.ticks(d as i64, timebase).max(0) as u64);
 
-        let mut origin = (v).as_ptr() as usize;
-        let mut needed = 0usize;
-        let offset;
-        loop {
-            if needed > 0 {
-                let len = needed + v.len();
-                v.resize(len, 0);
-                origin = (v).as_ptr() as usize;
-            }
+        if let Some(cluster_timestamp) = self.timestamp {
+            let relative = timestamp as i64 - cluster_timestamp as i64;
+            let max_duration =
+                self.ticks(self.cluster_max_duration as i64, Rational64::new(1, 1000));
 
-            match gen_simple_block_header(&s)((&mut v, 0)) {
-                Err(GenError::BufferTooSmall(sz)) => {
-                    needed = sz;
-                }
-                Err(e) => {
-                    error!("{:?}", e);
-                    return Err(Error::InvalidData);
-                }
-                Ok((sl, sz)) => {
-                    offset = sl.as_ptr() as usize + sz - origin;
-                    break;
-                }
-            };
+            if i16::try_from(relative).is_err()
+                || relative >= max_duration
+                || self.blocks_len + pkt.data.len() > self.cluster_max_size
+                || (pkt.is_key && self.is_video(track_number))
+            {
+                self.write_cluster(out)?;
+            }
         }
-        v.truncate(offset);
+        self.timestamp.get_or_insert(timestamp);
 
-        v.extend(pkt.data.iter());
-        let len = v.len();
-        self.blocks.push(v);
-        self.blocks_len += len;
+        let track = self.tracks.as_ref().and_then(|t| t.find(track_number));
+        let is_subtitle = track.is_some_and(|t| t.track_type == u64::from(TrackType::Subtitle));
+        let is_opus = track.is_some_and(|t| t.codec_id == "A_OPUS");
+        let default_duration = self.default_duration(track_number);
 
-        self.timestamp = if self.timestamp.is_none() {
-            Some(pkt.t.pts.or(pkt.t.dts).unwrap_or(0) as u64)
-        } else {
-            return Err(Error::InvalidData);
+        // Only a BlockGroup can hold a duration other than the default one
+        let block_duration = match (duration, default_duration) {
+            (Some(d), Some(default)) if d != default => Some(d),
+            (Some(d), None) if is_subtitle => Some(d),
+            _ => None,
+        };
+        // The end of the last Opus packet is trimmed, in nanoseconds
+        let discard_padding = match (pkt.t.duration, track.and_then(|t| t.default_duration)) {
+            (Some(d), Some(default)) if is_opus => {
+                let padding = default as i64 - rescale(d as i64, timebase, 1);
+                Some(padding).filter(|&p| p > 0)
+            }
+            _ => None,
         };
+        let previous = self.last_timestamps.insert(track_number, timestamp);
+        let reference_block =
+            if !pkt.is_key && (block_duration.is_some() || discard_padding.is_some()) {
+                previous.map(|p| p as i64 - timestamp as i64)
+            } else {
+                None
+            };
 
-        if pkt.is_key || self.blocks_len >= 5242880 {
+        let is_laced =
+            self.is_laced(track_number) && block_duration.is_none() && discard_padding.is_none();
+        match self.blocks.last_mut() {
+            Some(block)
+                if is_laced
+                    && default_duration.is_some_and(|d| {
+                        block.track_number == track_number
+                            && block.keyframe == pkt.is_key
+                            && !block.is_group()
+                            && block.frames.len() < MAX_LACED_FRAMES
+                            && block.timestamp + block.frames.len() as u64 * d == timestamp
+                    }) =>
             {
-                let simple_blocks: Vec<&[u8]> = self.blocks.iter().map(|v| &v[..]).collect();
-
-                let cluster = Cluster {
-                    timestamp: self.timestamp.take().unwrap(),
-                    position: None,
-                    prev_size: None,
-                    simple_block: simple_blocks,
-                    block_group: Vec::new(),
-                };
-
-                let mut buf: Vec<u8> = vec![0; cluster.size(0x1F43B675)];
-                let mut origin = (buf).as_ptr() as usize;
-                let mut needed = 0usize;
-                let offset;
-                loop {
-                    if needed > 0 {
-                        let len = needed + buf.len();
-                        buf.resize(len, 0);
-                        origin = (buf).as_ptr() as usize;
-                    }
-
-                    match gen_cluster(&cluster)((&mut buf, 0)) {
-                        Err(GenError::BufferTooSmall(sz)) => {
-                            needed = sz;
-                        }
-                        Err(e) => {
-                            error!("{:?}", e);
-                            return Err(Error::InvalidData);
-                        }
-                        Ok((sl, sz)) => {
-                            offset = sl.as_ptr() as usize + sz - origin;
-                            break;
-                        }
-                    };
-                }
-                buf.truncate(offset);
-                out.write_all(&buf).unwrap();
+                block.frames.push(pkt.data.clone());
+                block.duration = block.duration.zip(duration).map(|(a, b)| a + b);
             }
-
-            self.blocks.truncate(0);
-            self.blocks_len = 0;
+            _ => self.blocks.push(PendingBlock {
+                track_number,
+                timestamp,
+                keyframe: pkt.is_key,
+                duration,
+                frames: vec![pkt.data.clone()],
+                block_duration,
+                reference_block,
+                discard_padding,
+            }),
         }
+        self.blocks_len += pkt.data.len();
+        let end_timestamp = timestamp + duration.unwrap_or(0);
+        self.end_timestamp = self.end_timestamp.max(Some(end_timestamp));
 
         Ok(())
     }
 
+    /// Writes the last Cluster and the Cues.
+    ///
+    /// The header written by `write_header` lacks the Segment size, the
+    /// Duration and the position of the Cues. [MkvMuxer::finalize] has to be
+    /// called afterwards on a seekable output to patch them. Give the
+    /// [Context] a `&mut MkvMuxer` to keep the muxer around.
     fn write_trailer<W: Write>(&mut self, out: &mut Writer<W>) -> Result<()> {
-        let nb = self.blocks.len();
+        self.write_cluster(out)?;
 
-        if nb > 0 {
-            let simple_blocks: Vec<&[u8]> = self.blocks.iter().map(|v| &v[..]).collect();
-
-            let cluster = Cluster {
-                timestamp: self.timestamp.take().unwrap(),
-                position: None,
-                prev_size: None,
-                simple_block: simple_blocks,
-                block_group: Vec::new(),
+        if !self.cue_points.is_empty() {
+            let cues = Cues {
+                cue_points: sort_cue_points(std::mem::take(&mut self.cue_points)),
             };
+            let cues = self.crc32_element(serialize(|i| gen_cues(&cues)(i))?)?;
+            // The rest of the header Void must still fit a Void
+            let fits =
+                cues.len() == self.header_void_size || cues.len() + 2 <= self.header_void_size;
 
-            let mut buf: Vec<u8> = vec![0; cluster.size(0x1F43B675)];
-            let mut origin = (buf).as_ptr() as usize;
-            let mut needed = 0usize;
-            let offset;
-            loop {
-                if needed > 0 {
-                    let len = needed + buf.len();
-                    buf.resize(len, 0);
-                    origin = (buf).as_ptr() as usize;
-                }
-
-                match gen_cluster(&cluster)((&mut buf, 0)) {
-                    Err(GenError::BufferTooSmall(sz)) => {
-                        needed = sz;
-                    }
-                    Err(e) => {
-                        error!("{:?}", e);
-                        return Err(Error::InvalidData);
-                    }
-                    Ok((sl, sz)) => {
-                        offset = sl.as_ptr() as usize + sz - origin;
-                        break;
-                    }
-                };
-            }
-            buf.truncate(offset);
-            out.write_all(&buf).unwrap();
+            let position = if self.cues_at_front && fits {
+                self.front_cues = Some(cues);
+                self.header_void_position
+            } else {
+                let position = out.position() as u64 - self.segment_offset;
+                out.write_all(&cues)?;
+                position
+            };
+            self.seek_head.positions.push(Seek {
+                id: [0x1C, 0x53, 0xBB, 0x6B],
+                position,
+            });
         }
 
         Ok(())
@@ -438,23 +790,148 @@ impl Muxer for MkvMuxer {
         self.tracks = Some(Tracks {
             tracks: info.streams.iter().map(stream_to_track).collect(),
         });
+        self.timebases = info
+            .streams
+            .iter()
+            .map(|s| (s.index as u64 + 1, s.timebase))
+            .collect();
 
-        self.info = Some(Info {
-            muxing_app: String::from("rust-av"),
-            writing_app: String::from("rust-av"),
-            duration: info.duration.map(|d| d as f64),
-            timestamp_scale: 1000000,
-            ..Default::default()
-        });
+        // Keep the Info options already set
+        self.info.get_or_insert_with(default_info).duration = info.duration.map(|d| d as f64);
 
         Ok(())
     }
 
-    fn set_option(&mut self, _key: &str, _val: Value<'_>) -> Result<()> {
+    fn set_option(&mut self, key: &str, val: Value<'_>) -> Result<()> {
+        match (key, val) {
+            ("title", Value::Str(title)) => self.info_mut().title = Some(title.to_owned()),
+            ("writing_app", Value::Str(app)) => self.info_mut().writing_app = app.to_owned(),
+            ("muxing_app", Value::Str(app)) => self.info_mut().muxing_app = app.to_owned(),
+            ("segment_uid", Value::Str(uid)) => {
+                let uid = Uuid::parse_str(uid).map_err(|_| Error::InvalidData)?;
+                self.info_mut().segment_uid = Some(uid);
+            }
+            ("timestamp_scale", val) => match unsigned(val)? {
+                0 => return Err(Error::InvalidData),
+                scale => self.info_mut().timestamp_scale = scale,
+            },
+            ("cluster_max_duration", val) => self.cluster_max_duration = unsigned(val)?,
+            ("cluster_max_size", val) => self.cluster_max_size = unsigned(val)? as usize,
+            ("cues", Value::Bool(cues)) => self.cues = cues,
+            ("cues_placement", Value::Str("end")) => self.cues_at_front = false,
+            ("cues_placement", Value::Str("front")) => self.cues_at_front = true,
+            ("crc32", Value::Bool(crc32)) => self.crc32 = crc32,
+            // A Void takes at least 2 bytes
+            ("header_void_size", val) => match unsigned(val)? {
+                1 => return Err(Error::InvalidData),
+                size => self.header_void_size = size as usize,
+            },
+            ("doc_type_version", val) => match u32::try_from(unsigned(val)?) {
+                Ok(version) if version >= self.header.doc_type_read_version => {
+                    self.header.doc_type_version = version
+                }
+                _ => return Err(Error::InvalidData),
+            },
+            (key, val) => {
+                error!("invalid option {key}: {val:?}");
+                return Err(Error::InvalidData);
+            }
+        }
+
         Ok(())
     }
 }
 
+// Lets a Context borrow the muxer, which is needed again by finalize
+impl Muxer for &mut MkvMuxer {
+    fn configure(&mut self) -> Result<()> {
+        (**self).configure()
+    }
+
+    fn write_header<W: Write>(&mut self, out: &mut Writer<W>) -> Result<()> {
+        (**self).write_header(out)
+    }
+
+    fn write_packet<W: Write>(&mut self, out: &mut Writer<W>, pkt: Arc<Packet>) -> Result<()> {
+        (**self).write_packet(out, pkt)
+    }
+
+    fn write_trailer<W: Write>(&mut self, out: &mut Writer<W>) -> Result<()> {
+        (**self).write_trailer(out)
+    }
+
+    fn set_global_info(&mut self, info: GlobalInfo) -> Result<()> {
+        (**self).set_global_info(info)
+    }
+
+    fn set_option(&mut self, key: &str, val: Value<'_>) -> Result<()> {
+        (**self).set_option(key, val)
+    }
+}
+
+// Serializes an element, growing the buffer as needed
+fn serialize<G>(gen: G) -> Result<Vec<u8>>
+where
+    G: for<'b> Fn((&'b mut [u8], usize)) -> std::result::Result<(&'b mut [u8], usize), GenError>,
+{
+    let mut buf = vec![0; 1024];
+
+    loop {
+        match gen((&mut buf, 0)) {
+            Ok((_, len)) => {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+            // Only the shortfall of the failed write is reported
+            Err(GenError::BufferTooSmall(sz)) => buf.resize((buf.len() + sz).max(buf.len() * 2), 0),
+            Err(e) => {
+                error!("{:?}", e);
+                return Err(Error::InvalidData);
+            }
+        }
+    }
+}
+
+// Inserts a CRC-32 Element first in the data of a serialized master
+// Element, using `widen` more bytes than before for its size
+fn with_crc32(element: &[u8], widen: u8) -> Result<Vec<u8>> {
+    let (sized, _) = vid(element).map_err(|_| Error::InvalidData)?;
+    let (data, _) = elem_size(sized).map_err(|_| Error::InvalidData)?;
+    let id = &element[..element.len() - sized.len()];
+    let size = (data.len() + 6) as u64;
+    let size_len = ((sized.len() - data.len()) as u8 + widen)
+        .max(vint_size(size).map_err(|_| Error::InvalidData)?);
+    let checksum = CRC.checksum(data).to_le_bytes();
+
+    serialize(|i| {
+        tuple((
+            gen_slice(id),
+            gen_vint_l(size, size_len),
+            gen_slice(&[0xBF, 0x84]),
+            gen_slice(&checksum),
+            gen_slice(data),
+        ))(i)
+    })
+}
+
+// Accepts the integer values which are not negative
+fn unsigned(val: Value<'_>) -> Result<u64> {
+    match val {
+        Value::U64(v) => Ok(v),
+        Value::I64(v) => u64::try_from(v).map_err(|_| Error::InvalidData),
+        _ => Err(Error::InvalidData),
+    }
+}
+
+fn default_info() -> Info {
+    Info {
+        muxing_app: String::from("rust-av"),
+        writing_app: String::from("rust-av"),
+        timestamp_scale: 1000000,
+        ..Default::default()
+    }
+}
+
 #[allow(dead_code)]
 fn offset<'a>(original: &(&'a [u8], usize), subslice: &(&'a [u8], usize)) -> usize {
     let first = original.0.as_ptr() as usize;
@@ -479,23 +956,42 @@ fn gen_mkv_prefix<'b>(
     ))(input)
 }
 
+// The stream delay is in samples, the CodecDelay in nanoseconds
+fn codec_delay(s: &Stream) -> u64 {
+    match s.params.kind {
+        Some(MediaKind::Audio(ref a)) if a.rate > 0 => {
+            (s.params.delay as f64 * 1e9 / a.rate as f64).round() as u64
+        }
+        _ => 0,
+    }
+}
+
+/// Describes a [Stream] as a TrackEntry, along with the [TrackProperties]
+/// attached to it, if any.
 pub fn stream_to_track(s: &Stream) -> TrackEntry {
-    let codec_id = match s.params.codec_id.as_deref() {
-        Some("opus") => String::from("A_OPUS"),
-        Some("vorbis") => String::from("A_VORBIS"),
-        Some("av1") => String::from("V_AV1"),
-        Some("vp8") => String::from("V_VP8"),
-        Some("vp9") => String::from("V_VP9"),
-        _ => String::from("INVALID_CODEC"),
-    };
+    let name = s.params.codec_id.as_deref();
+    let native = name.and_then(codec::codec_id);
+    let properties = s
+        .user_private
+        .as_ref()
+        .and_then(|p| p.downcast_ref::<TrackProperties>())
+        .cloned()
+        .unwrap_or_default();
 
     let mut t = TrackEntry {
         track_uid: s.id as u64,
         track_number: s.index as u64 + 1,
         track_type: 0,
-        codec_id,
-        default_duration: s.duration,
-        codec_delay: s.params.delay as u64,
+        codec_id: native.unwrap_or("INVALID_CODEC").to_owned(),
+        default_duration: properties.frame_rate.and_then(frame_duration),
+        track_timestamp_scale: 1.0,
+        flag_enabled: 1,
+        flag_default: u64::from(properties.default),
+        flag_forced: u64::from(properties.forced),
+        flag_lacing: 1,
+        name: properties.name.clone(),
+        language: String::from("und"),
+        codec_delay: codec_delay(s),
         codec_private: s.params.extradata.clone(),
         seek_pre_roll: s.params.convergence_window as u64,
         ..Default::default()
@@ -504,37 +1000,815 @@ pub fn stream_to_track(s: &Stream) -> TrackEntry {
     match s.params.kind {
         Some(MediaKind::Video(ref v)) => {
             t.track_type = TrackType::Video.into();
+            let (display_width, display_height) =
+                display_size(v.width, v.height, properties.sample_aspect_ratio);
             t.video = Some(Video {
                 pixel_width: v.width as u64,
                 pixel_height: v.height as u64,
-                colour: Some(Colour {
-                    matrix_coefficients: match v.format.as_ref() {
-                        Some(fmt) => fmt.get_matrix() as u64,
-                        None => 0u64,
-                    },
-                    transfer_characteristics: match v.format.as_ref() {
-                        Some(fmt) => fmt.get_xfer() as u64,
-                        None => 0u64,
-                    },
-                    primaries: match v.format.as_ref() {
-                        Some(fmt) => fmt.get_primaries() as u64,
-                        None => 0u64,
-                    },
-                    ..Default::default()
-                }),
+                display_width,
+                display_height,
+                colour: v.format.as_deref().and_then(format_colour),
                 ..Default::default()
             });
         }
         Some(MediaKind::Audio(ref a)) => {
             t.track_type = TrackType::Audio.into();
-            t.audio = Some(Audio {
+            let mut audio = Audio {
                 sampling_frequency: a.rate as f64,
-                channels: 1,
+                channels: audio_channels(a) as u64,
+                bit_depth: a.format.as_ref().map(|f| u64::from(f.bits)),
                 ..Default::default()
-            });
+            };
+            if name == Some("aac") {
+                sbr_sampling_frequency(&mut audio, s.params.extradata.as_deref());
+            }
+            t.audio = Some(audio);
+        }
+        None => {
+            // Subtitles are told apart by their codec
+            let track_type = native.and_then(TrackType::from_codec_id);
+            if let Some(track_type) = track_type {
+                t.track_type = track_type.into();
+            }
+            // A Block holds a single subtitle with its own duration
+            if track_type == Some(TrackType::Subtitle) {
+                t.flag_lacing = 0;
+            }
         }
-        _ => {}
+    }
+
+    if let Some(language) = properties.language.as_deref() {
+        set_language(&mut t, language);
+    }
+
+    if let (None, Some(name)) = (native, name) {
+        set_compat_codec(&mut t, s, name);
     }
 
     t
 }
+
+// The Language only takes ISO 639-2 codes, the LanguageIETF supersedes it
+fn set_language(t: &mut TrackEntry, language: &str) {
+    if language.len() == 3 && language.bytes().all(|b| b.is_ascii_lowercase()) {
+        t.language = language.to_owned();
+    } else {
+        t.language_ietf = Some(language.to_owned());
+    }
+}
+
+// Duration of a frame in nanoseconds
+fn frame_duration(frame_rate: Rational64) -> Option<u64> {
+    if *frame_rate.numer() <= 0 || *frame_rate.denom() <= 0 {
+        return None;
+    }
+
+    let duration = (Rational64::from_integer(1_000_000_000) / frame_rate).round();
+    Some(duration.to_integer() as u64).filter(|&d| d > 0)
+}
+
+// Stretches the picture along one dimension to make the pixels square
+fn display_size(
+    width: usize,
+    height: usize,
+    sample_aspect_ratio: Option<Rational64>,
+) -> (Option<u64>, Option<u64>) {
+    let one = Rational64::from_integer(1);
+    let Some(sar) = sample_aspect_ratio.filter(|r| *r.numer() > 0 && *r.denom() > 0 && *r != one)
+    else {
+        return (None, None);
+    };
+
+    let scale = |size: usize, ratio: Rational64| {
+        (Rational64::from_integer(size as i64) * ratio)
+            .round()
+            .to_integer() as u64
+    };
+    if sar > one {
+        (Some(scale(width, sar)), Some(height as u64))
+    } else {
+        (Some(width as u64), Some(scale(height, sar.recip())))
+    }
+}
+
+// Describes a pixel format as a Colour, if it tells anything the defaults do not
+fn format_colour(format: &Formaton) -> Option<Colour> {
+    let unspecified = Colour {
+        matrix_coefficients: 2,
+        transfer_characteristics: 2,
+        primaries: 2,
+        ..Default::default()
+    };
+
+    let mut colour = Colour {
+        matrix_coefficients: format.get_matrix() as u64,
+        transfer_characteristics: format.get_xfer() as u64,
+        primaries: format.get_primaries() as u64,
+        bits_per_channel: format.get_chromaton(0).map_or(0, |c| u64::from(c.depth)),
+        ..unspecified.clone()
+    };
+
+    if let ColorModel::Trichromatic(TrichromaticEncodingSystem::YUV(system)) = format.get_model() {
+        if let YUVSystem::YCbCr(range) = system {
+            colour.range = match range {
+                YUVRange::Limited => 1,
+                YUVRange::Full => 2,
+            };
+        }
+        // ChromaSubsampling counts the chroma samples removed per sample
+        // kept, while a Chromaton takes its log2
+        if let Some(chroma) = format.get_chromaton(1) {
+            colour.chroma_subsampling_horz = Some((1 << chroma.h_ss) - 1);
+            colour.chroma_subsampling_vert = Some((1 << chroma.v_ss) - 1);
+        }
+    }
+
+    // Siting is 1 for collocated and 2 for half a sample away, the bottom
+    // is not representable
+    (colour.chroma_siting_horz, colour.chroma_siting_vert) = match format.chroma_location {
+        ChromaLocation::TopLeft => (1, 1),
+        ChromaLocation::Left => (1, 2),
+        ChromaLocation::Top => (2, 1),
+        ChromaLocation::Center => (2, 2),
+        ChromaLocation::BottomLeft => (1, 0),
+        ChromaLocation::Bottom => (2, 0),
+        ChromaLocation::Unspecified => (0, 0),
+    };
+
+    Some(colour).filter(|c| *c != unspecified)
+}
+
+fn audio_channels(a: &AudioInfo) -> usize {
+    a.map.as_ref().map_or(1, |m| m.len().max(1))
+}
+
+// SBR AAC is signaled with the core sampling frequency and the output one
+fn sbr_sampling_frequency(audio: &mut Audio, extradata: Option<&[u8]>) {
+    let Some(asc) = extradata.and_then(AudioSpecificConfig::parse) else {
+        return;
+    };
+
+    let core = asc.sample_rate;
+    let output = match asc.extension_sample_rate {
+        Some(rate) => rate,
+        // Implicit signaling only shows in the doubled output sample rate
+        None if audio.sampling_frequency == f64::from(core * 2) => core * 2,
+        None => return,
+    };
+
+    if output != core {
+        audio.sampling_frequency = f64::from(core);
+        audio.output_sampling_frequency = Some(f64::from(output));
+    }
+}
+
+// Describes a codec without a native codec ID with a BITMAPINFOHEADER or a WAVEFORMATEX
+fn set_compat_codec(t: &mut TrackEntry, s: &Stream, name: &str) {
+    let extradata = s.params.extradata.as_deref().unwrap_or_default();
+
+    match s.params.kind {
+        Some(MediaKind::Video(ref v)) => {
+            if let Some(compression) = codec::codec_fourcc(name) {
+                // None of the FourCCs is uncompressed RGB, which would need an image size
+                let header = BitmapInfoHeader {
+                    width: v.width as i32,
+                    height: v.height as i32,
+                    planes: 1,
+                    bit_count: v
+                        .format
+                        .as_ref()
+                        .map_or(0, |f| u16::from(f.get_total_depth())),
+                    compression,
+                    ..Default::default()
+                };
+                t.codec_id = String::from("V_MS/VFW/FOURCC");
+                t.codec_private = Some(header.to_bytes(extradata));
+            }
+        }
+        Some(MediaKind::Audio(ref a)) => {
+            if let Some(format_tag) = codec::codec_format_tag(name) {
+                let channels = audio_channels(a) as u16;
+                let bits_per_sample = a.format.as_ref().map_or(0, |f| u16::from(f.bits));
+                // The block size of the compressed formats is only known to their encoder
+                let block_align = match name {
+                    "pcm_int_le" | "pcm_float" | "pcm_alaw" | "pcm_mulaw" => {
+                        channels * bits_per_sample / 8
+                    }
+                    _ => 0,
+                };
+                // The default layouts of mono and stereo do not need a mask
+                let channel_mask = match a.map {
+                    Some(ref map) if channels > 2 => channel_mask(map).unwrap_or(0),
+                    _ => 0,
+                };
+                let wf = WaveFormatEx {
+                    format_tag,
+                    channels,
+                    samples_per_sec: a.rate as u32,
+                    avg_bytes_per_sec: (s.params.bit_rate / 8) as u32,
+                    block_align,
+                    bits_per_sample,
+                    channel_mask,
+                };
+                t.codec_id = String::from("A_MS/ACM");
+                t.codec_private = Some(wf.to_bytes(extradata));
+            }
+        }
+        _ => {}
+    }
+}
+
+// Converts a time in `timebase` units to units of `scale` nanoseconds
+fn rescale(t: i64, timebase: Rational64, scale: u64) -> i64 {
+    let num = t as i128 * *timebase.numer() as i128 * 1_000_000_000;
+    let den = *timebase.denom() as i128 * scale as i128;
+    (num + den / 2).div_euclid(den) as i64
+}
+
+// Picks the lacing with the smallest header for the frames of a Block
+fn block_lacing(frames: &[&[u8]]) -> Lacing {
+    [Lacing::None, Lacing::FixedSize, Lacing::Xiph, Lacing::EBML]
+        .into_iter()
+        .filter_map(|lacing| lacing_size(lacing, frames).map(|size| (size, lacing)))
+        .min_by_key(|&(size, _)| size)
+        .map_or(Lacing::None, |(_, lacing)| lacing)
+}
+
+// The CuePoints ordered by time, the ones sharing a time are merged
+fn sort_cue_points(mut cue_points: Vec<CuePoint>) -> Vec<CuePoint> {
+    cue_points.sort_by_key(|point| point.time);
+
+    let mut sorted: Vec<CuePoint> = Vec::with_capacity(cue_points.len());
+    for point in cue_points {
+        match sorted.last_mut() {
+            Some(last) if last.time == point.time => {
+                last.track_positions.extend(point.track_positions)
+            }
+            _ => sorted.push(point),
+        }
+    }
+
+    sorted
+}
+
+fn is_video(track: &TrackEntry) -> bool {
+    track.track_type == u64::from(TrackType::Video)
+}
+
+#[cfg(test)]
+#[allow(non_upper_case_globals)]
+mod tests {
+    use std::io::{BufRead, Cursor};
+
+    use av_data::{params::CodecParams, timeinfo::TimeInfo};
+    use av_format::{
+        buffer::AccReader,
+        demuxer::{self, Demuxer as _, Event},
+    };
+
+    use crate::{
+        codec::channels::default_channel_map,
+        demuxer::{track_to_stream, MkvDemuxer, SeekFlags},
+        ebml::elem_size,
+        elements::{block, segment_element, simple_block, SegmentElement},
+    };
+
+    use super::*;
+
+    const webm: &[u8] = include_bytes!("../assets/bbb-vp9-opus.webm");
+
+    // The streams and the packets of the WebM sample
+    fn webm_packets() -> (Vec<Stream>, Vec<Packet>) {
+        let mut context =
+            demuxer::Context::new(MkvDemuxer::new(), AccReader::new(Cursor::new(webm)));
+        context.read_headers().unwrap();
+
+        let mut packets = Vec::new();
+        while let Ok(event) = context.read_event() {
+            match event {
+                Event::NewPacket(p) => packets.push(p),
+                Event::Eof => break,
+                _ => {}
+            }
+        }
+
+        (context.info.streams.clone(), packets)
+    }
+
+    fn stream(
+        index: usize,
+        kind: Option<MediaKind>,
+        codec_id: &str,
+        timebase: Rational64,
+        frame_rate: Option<Rational64>,
+    ) -> Stream {
+        Stream {
+            id: index as isize + 1,
+            index,
+            start: None,
+            duration: None,
+            timebase,
+            params: CodecParams {
+                kind,
+                codec_id: Some(codec_id.into()),
+                extradata: None,
+                bit_rate: 0,
+                convergence_window: 0,
+                delay: 0,
+            },
+            // Written as the DefaultDuration of the track
+            user_private: Some(Arc::new(TrackProperties {
+                frame_rate,
+                ..Default::default()
+            })),
+        }
+    }
+
+    fn stereo() -> MediaKind {
+        MediaKind::Audio(AudioInfo {
+            rate: 48000,
+            map: default_channel_map(2),
+            format: None,
+        })
+    }
+
+    fn packet(stream_index: isize, pts: i64, duration: u64, is_key: bool, data: Vec<u8>) -> Packet {
+        Packet {
+            data,
+            pos: None,
+            stream_index,
+            t: TimeInfo {
+                pts: Some(pts),
+                duration: Some(duration),
+                ..Default::default()
+            },
+            is_key,
+            is_corrupted: false,
+        }
+    }
+
+    fn global_info(streams: Vec<Stream>) -> GlobalInfo {
+        GlobalInfo {
+            duration: None,
+            timebase: None,
+            streams,
+        }
+    }
+
+    // Writes the packets with a configured muxer, then finalizes the file
+    fn mux(mut mkv: MkvMuxer, streams: Vec<Stream>, packets: &[Packet]) -> Vec<u8> {
+        let mut muxer = Context::new(&mut mkv, Writer::new(Cursor::new(Vec::new())));
+        muxer.set_global_info(global_info(streams)).unwrap();
+        muxer.write_header().unwrap();
+        for packet in packets.iter() {
+            muxer.write_packet(Arc::new(packet.clone())).unwrap();
+        }
+        muxer.write_trailer().unwrap();
+        let mut writer = muxer.into_writer();
+        mkv.finalize(&mut writer).unwrap();
+        writer.as_ref().0.get_ref().clone()
+    }
+
+    // The demuxer once it read the headers, with its buffer
+    fn demux_headers(data: &[u8]) -> (MkvDemuxer, AccReader<Cursor<&[u8]>>) {
+        let mut demuxer = MkvDemuxer::new();
+        let mut info = global_info(Vec::new());
+        let mut buf = AccReader::new(Cursor::new(data));
+        buf.fill_buf().unwrap();
+        demuxer.read_headers(&mut buf, &mut info).unwrap();
+        (demuxer, buf)
+    }
+
+    fn demux_packets(data: Vec<u8>) -> Vec<Packet> {
+        let mut context =
+            demuxer::Context::new(MkvDemuxer::new(), AccReader::new(Cursor::new(data)));
+        context.read_headers().unwrap();
+
+        let mut packets = Vec::new();
+        while let Ok(Event::NewPacket(p)) = context.read_event() {
+            packets.push(p);
+        }
+        packets
+    }
+
+    // The Elements following the Tracks
+    fn segment_elements(data: &[u8]) -> Vec<SegmentElement<'_>> {
+        let mut demuxer = MkvDemuxer::new();
+        let (mut i, _) = demuxer.parse_until_tracks(data).unwrap();
+        let mut elements = Vec::new();
+        while let Ok((rest, element)) = segment_element(i) {
+            elements.push(element);
+            i = rest;
+        }
+        elements
+    }
+
+    #[test]
+    fn mux_finalize() {
+        let (streams, packets) = webm_packets();
+        let video_keyframes = packets
+            .iter()
+            .filter(|p| p.stream_index == 0 && p.is_key)
+            .count();
+        // The Duration is then found from the packets
+        let end_timestamp = packets
+            .iter()
+            .map(|p| p.t.pts.unwrap() + p.t.duration.unwrap_or(0) as i64)
+            .max()
+            .unwrap();
+
+        let data = mux(MkvMuxer::webm(), streams, &packets);
+        let (mut demuxer, mut buf) = demux_headers(&data);
+
+        let segment_offset = demuxer.segment_offset as usize;
+        let mut segment_size = [0; 8];
+        segment_size.copy_from_slice(&data[segment_offset - 8..segment_offset]);
+        assert_eq!(
+            u64::from_be_bytes(segment_size),
+            1 << 56 | (data.len() - segment_offset) as u64
+        );
+        assert_eq!(
+            demuxer.info.as_ref().unwrap().duration,
+            Some(end_timestamp as f64)
+        );
+        assert!(demuxer.tracks.is_some());
+
+        let cues_position = demuxer.cues_position().unwrap();
+        match segment_element(&data[cues_position as usize..]) {
+            Ok((_, SegmentElement::Cues(cues))) => {
+                assert!(video_keyframes > 0);
+                assert_eq!(cues.cue_points.len(), video_keyframes);
+                for point in cues.cue_points.iter() {
+                    let positions = &point.track_positions[0];
+                    assert_eq!(positions.track, 1);
+                    let cluster = demuxer.segment_offset + positions.cluster_position;
+                    match segment_element(&data[cluster as usize..]) {
+                        Ok((_, SegmentElement::Cluster(c))) => assert_eq!(c.timestamp, point.time),
+                        e => panic!("no Cluster at {cluster}: {e:?}"),
+                    }
+                }
+            }
+            e => panic!("no Cues at {cues_position}: {e:?}"),
+        }
+
+        let flags = SeekFlags {
+            keyframe: true,
+            backward: true,
+        };
+        let position = demuxer.seek(&mut buf, 0, 0, flags).unwrap();
+        assert!(demuxer.cues.is_some());
+        assert!(position > demuxer.segment_offset);
+
+        let demuxed = demux_packets(data);
+        assert_eq!(demuxed.len(), packets.len());
+        for (p, packet) in demuxed.iter().zip(packets.iter()) {
+            assert_eq!(p.stream_index, packet.stream_index);
+            assert_eq!(p.t.pts, packet.t.pts);
+            assert_eq!(p.is_key, packet.is_key);
+            assert_eq!(p.data, packet.data);
+        }
+    }
+
+    #[test]
+    fn mux_without_finalize() {
+        let (streams, packets) = webm_packets();
+
+        // The muxer is owned by the Context, as with any other Muxer
+        let mut muxer = Context::new(MkvMuxer::webm(), Writer::new(Cursor::new(Vec::new())));
+        muxer.set_global_info(global_info(streams)).unwrap();
+        muxer.write_header().unwrap();
+        for packet in packets.iter() {
+            muxer.write_packet(Arc::new(packet.clone())).unwrap();
+        }
+        muxer.write_trailer().unwrap();
+        let data = muxer.into_writer().as_ref().0.get_ref().clone();
+
+        // The SeekHead does not point to the Cues, which follow the Clusters
+        let (demuxer, _) = demux_headers(&data);
+        assert!(demuxer.tracks.is_some());
+        assert_eq!(demuxer.info.as_ref().unwrap().duration, None);
+        assert_eq!(demuxer.cues_position(), None);
+        match segment_elements(&data).last() {
+            Some(SegmentElement::Cues(cues)) => assert!(!cues.cue_points.is_empty()),
+            e => panic!("no Cues at the end: {e:?}"),
+        }
+
+        let demuxed = demux_packets(data);
+        assert_eq!(demuxed.len(), packets.len());
+        for (p, packet) in demuxed.iter().zip(packets.iter()) {
+            assert_eq!(p.stream_index, packet.stream_index);
+            assert_eq!(p.t.pts, packet.t.pts);
+            assert_eq!(p.data, packet.data);
+        }
+    }
+
+    #[test]
+    fn mux_options() {
+        let (streams, packets) = webm_packets();
+
+        let mut mkv = MkvMuxer::webm();
+        for (key, val) in [
+            ("unknown", Value::Bool(true)),
+            ("title", Value::U64(1)),
+            ("crc32", Value::Str("yes")),
+            ("segment_uid", Value::Str("not a uuid")),
+            ("timestamp_scale", Value::U64(0)),
+            ("cluster_max_size", Value::I64(-1)),
+            ("cues_placement", Value::Str("middle")),
+            ("header_void_size", Value::U64(1)),
+            ("doc_type_version", Value::U64(0)),
+            ("cues_video_only", Value::Bool(false)),
+            ("lacing", Value::Bool(true)),
+        ] {
+            assert!(mkv.set_option(key, val).is_err(), "{key} accepted");
+        }
+        for (key, val) in [
+            ("title", Value::Str("options")),
+            ("writing_app", Value::Str("mux_options")),
+            (
+                "segment_uid",
+                Value::Str("6ba7b810-9dad-11d1-80b4-00c04fd430c8"),
+            ),
+            ("doc_type_version", Value::U64(2)),
+            ("crc32", Value::Bool(true)),
+            ("cues_placement", Value::Str("front")),
+            ("header_void_size", Value::I64(4096)),
+        ] {
+            mkv.set_option(key, val).unwrap();
+        }
+        let data = mux(mkv, streams, &packets);
+        let (demuxer, _) = demux_headers(&data);
+
+        assert_eq!(demuxer.header.as_ref().unwrap().doc_type_version, 2);
+        let segment_info = demuxer.info.as_ref().unwrap();
+        assert_eq!(segment_info.title.as_deref(), Some("options"));
+        assert_eq!(segment_info.writing_app, "mux_options");
+        assert_eq!(
+            segment_info.segment_uid,
+            Uuid::parse_str("6ba7b810-9dad-11d1-80b4-00c04fd430c8").ok()
+        );
+
+        // The Cues are written before the first Cluster, and every master
+        // Element starts with a CRC-32
+        let segment_offset = demuxer.segment_offset as usize;
+        let cues_position = demuxer.cues_position().unwrap() as usize;
+        assert!(cues_position < segment_offset + 4096);
+        let (cues_data, _) = elem_size(&data[cues_position + 4..]).unwrap();
+        assert_eq!(&cues_data[..2], &[0xBF, 0x84]);
+        let cues = match segment_element(&data[cues_position..]) {
+            Ok((_, SegmentElement::Cues(cues))) => cues,
+            e => panic!("no Cues at {cues_position}: {e:?}"),
+        };
+        assert!(!cues.cue_points.is_empty());
+        for point in cues.cue_points.iter() {
+            let positions = &point.track_positions[0];
+            let cluster = segment_offset + positions.cluster_position as usize;
+            assert!(cluster > cues_position);
+            let (cluster_data, _) = elem_size(&data[cluster + 4..]).unwrap();
+            assert_eq!(&cluster_data[..2], &[0xBF, 0x84]);
+            match segment_element(&data[cluster..]) {
+                Ok((_, SegmentElement::Cluster(c))) => assert_eq!(c.timestamp, point.time),
+                e => panic!("no Cluster at {cluster}: {e:?}"),
+            }
+            // The relative position takes the CRC-32 into account
+            let block = positions.relative_position.unwrap() as usize;
+            assert_eq!(cluster_data[block], 0xA3);
+        }
+
+        let demuxed = demux_packets(data);
+        assert_eq!(demuxed.len(), packets.len());
+        for (p, packet) in demuxed.iter().zip(packets.iter()) {
+            assert_eq!(p.stream_index, packet.stream_index);
+            assert_eq!(p.t.pts, packet.t.pts);
+            assert_eq!(p.data, packet.data);
+        }
+    }
+
+    #[test]
+    fn mux_clusters() {
+        let streams = vec![stream(
+            0,
+            Some(stereo()),
+            "pcm_s16le",
+            Rational64::new(1, 48000),
+            None,
+        )];
+        // 20 ms packets, for 40 seconds
+        let packets = (0..2000)
+            .map(|n| packet(0, n * 960, 960, true, vec![n as u8; 16]))
+            .collect::<Vec<_>>();
+
+        let mux = |options: &[(&str, u64)]| {
+            let mut mkv = MkvMuxer::matroska();
+            for &(key, value) in options {
+                mkv.set_option(key, Value::U64(value)).unwrap();
+            }
+            mux(mkv, streams.clone(), &packets)
+        };
+        let clusters = |data: &[u8]| {
+            segment_elements(data)
+                .into_iter()
+                .filter_map(|element| match element {
+                    SegmentElement::Cluster(c) => Some(c.timestamp),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        let data = mux(&[]);
+        assert_eq!(
+            clusters(&data),
+            (0..8).map(|n| n * 5000).collect::<Vec<_>>()
+        );
+
+        // Without video, only the first keyframe of each Cluster is cued
+        let cue_times = segment_elements(&data)
+            .into_iter()
+            .filter_map(|element| match element {
+                SegmentElement::Cues(c) => Some(c.cue_points),
+                _ => None,
+            })
+            .flatten()
+            .map(|p| p.time)
+            .collect::<Vec<_>>();
+        assert_eq!(cue_times, clusters(&data));
+
+        // The relative timestamps of the Blocks cannot go past 32767 ticks
+        let data = mux(&[("cluster_max_duration", 60000)]);
+        assert_eq!(clusters(&data), vec![0, 32780]);
+
+        let data = mux(&[("cluster_max_size", 1024)]);
+        assert_eq!(clusters(&data).len(), 2000 / 64 + 1);
+
+        let demuxed = demux_packets(data);
+        assert_eq!(demuxed.len(), packets.len());
+        for (n, p) in demuxed.iter().enumerate() {
+            assert_eq!(p.t.pts, Some(n as i64 * 20));
+            assert_eq!(p.data, vec![n as u8; 16]);
+        }
+    }
+
+    #[test]
+    fn mux_lacing() {
+        // 20 ms frames, written as the DefaultDuration of the track
+        let streams = vec![stream(
+            0,
+            Some(stereo()),
+            "opus",
+            Rational64::new(1, 1000),
+            Some(Rational64::new(50, 1)),
+        )];
+        // 20 ms packets, for 2 seconds, with a gap after the 50th one
+        let packets = (0..100)
+            .map(|n| {
+                let pts = n as i64 * 20 + if n < 50 { 0 } else { 100 };
+                packet(0, pts, 20, true, vec![n as u8; 10 + n % 3])
+            })
+            .collect::<Vec<_>>();
+
+        let mux = |lacing: bool| {
+            let mut mkv = MkvMuxer::webm();
+            mkv.set_lacing(lacing);
+            mux(mkv, streams.clone(), &packets)
+        };
+        let blocks = |data: &[u8]| {
+            let mut lacings = Vec::new();
+            for element in segment_elements(data) {
+                if let SegmentElement::Cluster(c) = element {
+                    for block in c.simple_blocks() {
+                        lacings.push(simple_block(block).unwrap().1.lacing);
+                    }
+                }
+            }
+            lacings
+        };
+
+        assert_eq!(blocks(&mux(false)), vec![Lacing::None; 100]);
+
+        // 8 frames at most per Block, which the gap interrupts
+        let data = mux(true);
+        let lacings = blocks(&data);
+        assert_eq!(lacings.len(), 7 + 7);
+        assert!(lacings.iter().all(|&l| l == Lacing::Xiph));
+
+        let demuxed = demux_packets(data);
+        assert_eq!(demuxed.len(), packets.len());
+        for (p, packet) in demuxed.iter().zip(packets.iter()) {
+            assert_eq!(p.t.pts, packet.t.pts);
+            assert_eq!(p.data, packet.data);
+        }
+    }
+
+    #[test]
+    fn mux_block_groups() {
+        let streams = vec![
+            stream(
+                0,
+                Some(stereo()),
+                "opus",
+                Rational64::new(1, 1000),
+                Some(Rational64::new(50, 1)),
+            ),
+            stream(1, None, "ass", Rational64::new(1, 1000), None),
+        ];
+
+        let packet = |stream_index, pts, duration, is_key| {
+            packet(stream_index, pts, duration, is_key, vec![pts as u8; 8])
+        };
+        // The last Opus packet is trimmed to 12 ms
+        let mut packets = (0..10)
+            .map(|n| packet(0, n * 20, if n < 9 { 20 } else { 12 }, true))
+            .collect::<Vec<_>>();
+        packets.insert(1, packet(1, 10, 1500, true));
+        packets.insert(6, packet(1, 110, 1500, false));
+
+        let data = mux(MkvMuxer::webm(), streams, &packets);
+        match segment_elements(&data).into_iter().next() {
+            Some(SegmentElement::Cluster(c)) => {
+                // The BlockGroups stay between the SimpleBlocks, in the packets order
+                let kinds = c
+                    .blocks
+                    .iter()
+                    .map(|b| matches!(b, ClusterBlock::BlockGroup(_)))
+                    .collect::<Vec<_>>();
+                let mut expected = vec![false; 12];
+                for n in [1, 6, 11] {
+                    expected[n] = true;
+                }
+                assert_eq!(kinds, expected);
+                let groups = c
+                    .block_groups()
+                    .map(|g| {
+                        (
+                            block(g.block).unwrap().1.track_number,
+                            g.block_duration,
+                            g.reference_block,
+                            g.discard_padding,
+                        )
+                    })
+                    .collect::<Vec<_>>();
+                assert_eq!(
+                    groups,
+                    vec![
+                        (2, Some(1500), None, None),
+                        (2, Some(1500), Some(-100), None),
+                        (1, Some(12), None, Some(8000000)),
+                    ]
+                );
+            }
+            e => panic!("no Cluster: {e:?}"),
+        }
+
+        let demuxed = demux_packets(data)
+            .iter()
+            .map(|p| (p.stream_index, p.t.pts, p.t.duration, p.is_key))
+            .collect::<Vec<_>>();
+        let expected = packets
+            .iter()
+            .map(|p| (p.stream_index, p.t.pts, p.t.duration, p.is_key))
+            .collect::<Vec<_>>();
+        assert_eq!(demuxed, expected);
+    }
+
+    #[test]
+    fn cue_points_order() {
+        let point = |time, track| CuePoint {
+            time,
+            track_positions: vec![CueTrackPositions {
+                track,
+                cluster_position: 0,
+                relative_position: None,
+                duration: None,
+                block_number: None,
+                codec_state: 0,
+                references: Vec::new(),
+            }],
+        };
+
+        let sorted = sort_cue_points(vec![point(40, 1), point(20, 2), point(40, 2), point(0, 1)]);
+        assert_eq!(
+            sorted
+                .iter()
+                .map(|p| (p.time, p.track_positions.iter().map(|t| t.track).collect()))
+                .collect::<Vec<(u64, Vec<u64>)>>(),
+            vec![(0, vec![1]), (20, vec![2]), (40, vec![1, 2])]
+        );
+    }
+
+    #[test]
+    fn codec_delay_round_trip() {
+        let mut demuxer = MkvDemuxer::new();
+        demuxer.parse_until_tracks(webm).unwrap();
+        let info = demuxer.info.as_ref().unwrap();
+        let streams = demuxer.tracks.as_ref().unwrap().tracks.iter();
+        let streams = streams.map(|t| track_to_stream(info, t)).collect();
+
+        let data = mux(MkvMuxer::webm(), streams, &[]);
+
+        let mut demuxer = MkvDemuxer::new();
+        demuxer.parse_until_tracks(&data).unwrap();
+        let tracks = &demuxer.tracks.as_ref().unwrap().tracks;
+        let opus = tracks.iter().find(|t| t.codec_id == "A_OPUS").unwrap();
+        assert_eq!(opus.codec_delay, 6500000);
+    }
+}
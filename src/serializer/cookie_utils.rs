@@ -3,6 +3,9 @@ use cookie_factory::combinator::{skip, slice};
 use cookie_factory::gen::legacy_wrap;
 use cookie_factory::GenError;
 
+/// Output of the serializers, the buffer and the position after the written data.
+pub(crate) type GenResult<'a> = Result<(&'a mut [u8], usize), GenError>;
+
 pub(crate) fn gen_at_offset<G>(
     offset: usize,
     f: G,
@@ -4,7 +4,9 @@ use cookie_factory::GenError;
 use nom::AsBytes;
 
 use crate::ebml::{Date, EbmlHeader};
-use crate::serializer::cookie_utils::{gen_at_offset, gen_skip, gen_slice, set_be_f64, tuple};
+use crate::serializer::cookie_utils::{
+    gen_at_offset, gen_skip, gen_slice, set_be_f64, tuple, GenResult,
+};
 
 const ALLOWED_ID_VALUES: u64 = (1u64 << 56) - 1;
 
@@ -57,6 +59,19 @@ pub(crate) fn gen_vint(
     }
 }
 
+// Writes a vint on exactly `len` bytes
+pub(crate) fn gen_vint_l(num: u64, len: u8) -> impl Fn((&mut [u8], usize)) -> GenResult<'_> {
+    move |input| {
+        if len == 0 || len > 8 || vint_size(num)? > len {
+            return Err(GenError::CustomError(0));
+        }
+
+        let num = num | 1u64 << (len * 7);
+        let skip = 8 - len as usize;
+        gen_slice!(input, &num.to_be_bytes()[skip..])
+    }
+}
+
 pub(crate) fn gen_vid(
     num: u32,
 ) -> impl Fn((&mut [u8], usize)) -> Result<(&mut [u8], usize), GenError> {
@@ -141,7 +156,27 @@ where
         let (buf, ofs_len) = gen_vid(id)(input)?;
         let (buf, start) = gen_skip(expected_size as usize)((buf, ofs_len))?;
         let (buf, end) = f((buf, start))?;
-        gen_at_offset(ofs_len, gen_ebml_size(expected_size, end - start))((buf, end))
+        gen_at_offset(ofs_len, gen_vint_l((end - start) as u64, expected_size))((buf, end))
+    }
+}
+
+/// Writes a Void element taking exactly `size` bytes, nothing if `size` is 0.
+pub(crate) fn gen_void(size: usize) -> impl Fn((&mut [u8], usize)) -> GenResult<'_> {
+    move |input| {
+        if size == 0 {
+            return Ok(input);
+        }
+
+        // Large payloads need the widest size
+        let len = if size < 2 + 127 { 1 } else { 8 };
+        if size < 1 + len as usize {
+            return Err(GenError::CustomError(0));
+        }
+        let payload = size - 1 - len as usize;
+
+        let (buf, ofs) = gen_vid(0xEC)(input)?;
+        let (buf, ofs) = gen_vint_l(payload as u64, len)((buf, ofs))?;
+        gen_skip(payload)((buf, ofs))
     }
 }
 
@@ -171,6 +206,22 @@ pub(crate) fn gen_ebml_uint(
     gen_ebml_uint_l(id, num, move || vint_size(num))
 }
 
+/// Number of bytes of a signed integer in two's complement.
+pub(crate) fn int_size(num: i64) -> u8 {
+    (1..8)
+        .find(|&len| (-1 << (8 * len - 1)..1 << (8 * len - 1)).contains(&num))
+        .unwrap_or(8)
+}
+
+pub(crate) fn gen_ebml_int(id: u32, num: i64) -> impl Fn((&mut [u8], usize)) -> GenResult<'_> {
+    move |input| {
+        let len = int_size(num) as usize;
+        let input = gen_vid(id)(input)?;
+        let input = gen_vint(len as u64)(input)?;
+        gen_slice(&num.to_be_bytes()[8 - len..])(input)
+    }
+}
+
 pub(crate) fn gen_ebml_str<'a, 'b>(
     id: u32,
     s: &'a str,
@@ -261,7 +312,7 @@ impl EbmlSize for u64 {
 
 impl EbmlSize for i64 {
     fn capacity(&self) -> usize {
-        vint_size(*self as u64).unwrap_or(0) as usize
+        int_size(*self) as usize
     }
 }
 
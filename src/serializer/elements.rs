@@ -1,21 +1,33 @@
-use cookie_factory::gen::set_be_u8;
+use cookie_factory::gen::{set_be_u64, set_be_u8};
 use cookie_factory::GenError;
 
 use crate::{
     elements::{
-        Audio, Cluster, Colour, Info, Lacing, MasteringMetadata, Projection, Seek, SeekHead,
+        Audio, BlockGroup, Cluster, ClusterBlock, Colour, CuePoint, CueReference,
+        CueTrackPositions, Cues, Info, Lacing, MasteringMetadata, Projection, Seek, SeekHead,
         SimpleBlock, TrackEntry, Tracks, Video,
     },
-    serializer::cookie_utils::{gen_many, gen_opt, gen_opt_copy, set_be_i16, tuple},
+    serializer::cookie_utils::{
+        gen_many, gen_opt, gen_opt_copy, gen_slice, set_be_i16, tuple, GenResult,
+    },
     serializer::ebml::{
-        gen_ebml_binary, gen_ebml_master, gen_ebml_str, gen_ebml_uint, gen_ebml_uint_l, gen_f64,
-        gen_vid, gen_vint, vint_size, EbmlSize,
+        gen_ebml_binary, gen_ebml_int, gen_ebml_master, gen_ebml_str, gen_ebml_uint,
+        gen_ebml_uint_l, gen_f64, gen_vid, gen_vint, gen_vint_l, gen_void, vint_size, EbmlSize,
     },
 };
 
-pub(crate) fn gen_segment_header_unknown_size(
-) -> impl Fn((&mut [u8], usize)) -> Result<(&mut [u8], usize), GenError> {
-    move |input| tuple((gen_vid(0x18538067), |i| set_be_u8(i, 0xFF)))(input)
+/// Writes a Segment header with an 8 bytes size, which can be patched once known.
+///
+/// A `None` size is written as the unknown size.
+pub(crate) fn gen_segment_header(
+    size: Option<u64>,
+) -> impl Fn((&mut [u8], usize)) -> GenResult<'_> {
+    move |input| match size {
+        Some(size) => tuple((gen_vid(0x18538067), gen_vint_l(size, 8)))(input),
+        None => tuple((gen_vid(0x18538067), |i| {
+            set_be_u64(i, 0x01FF_FFFF_FFFF_FFFF)
+        }))(input),
+    }
 }
 
 impl EbmlSize for Seek {
@@ -56,6 +68,32 @@ pub(crate) fn gen_seek_head<'a, 'b>(
     }
 }
 
+/// Writes the SeekHead followed by a Void, taking exactly `reserved` bytes.
+pub(crate) fn gen_seek_head_reserved<'a, 'b>(
+    s: &'a SeekHead,
+    reserved: usize,
+) -> impl Fn((&'b mut [u8], usize)) -> GenResult<'b> + 'a {
+    move |input| {
+        let capacity = s.capacity();
+        let mut byte_capacity = vint_size(capacity as u64)?;
+        let size = 4 + byte_capacity as usize + capacity;
+        if size > reserved {
+            return Err(GenError::CustomError(0));
+        }
+
+        // A Void takes at least 2 bytes, widen the size instead
+        if reserved - size == 1 {
+            byte_capacity += 1;
+        }
+        let void = reserved - (4 + byte_capacity as usize + capacity);
+
+        tuple((
+            gen_ebml_master(0x114D9B74, byte_capacity, gen_many(&s.positions, gen_seek)),
+            gen_void(void),
+        ))(input)
+    }
+}
+
 impl EbmlSize for Info {
     fn capacity(&self) -> usize {
         self.segment_uid.size(0x73A4) + self.segment_filename.size(0x7384)
@@ -397,9 +435,18 @@ impl<'a> EbmlSize for Cluster<'a> {
         self.timestamp.size(0xE7)
             + self.position.size(0xA7)
             + self.prev_size.size(0xAB)
-            + self.simple_block.size(0xA3)
-        // TODO: implement for BlockGroup
-        // + self.block_group.size(0xA0)
+            + self
+                .blocks
+                .iter()
+                .fold(0, |acc, block| acc + block_size(block))
+    }
+}
+
+/// Size of a SimpleBlock or BlockGroup Element.
+pub(crate) fn block_size(block: &ClusterBlock) -> usize {
+    match block {
+        ClusterBlock::SimpleBlock(data) => data.size(0xA3),
+        ClusterBlock::BlockGroup(group) => group.size(0xA0),
     }
 }
 
@@ -415,14 +462,151 @@ pub(crate) fn gen_cluster<'a, 'b>(
                 gen_ebml_uint(0xE7, c.timestamp),
                 gen_opt_copy(c.position, |v| gen_ebml_uint(0xA7, v)),
                 gen_opt_copy(c.prev_size, |v| gen_ebml_uint(0xAB, v)),
-                gen_many(&c.simple_block, |v| gen_ebml_binary(0xA3, v)),
-                // TODO: implement for BlockGroup
-                // gen_many(&c.block_group, gen_block_group)
+                gen_many(&c.blocks, gen_cluster_block),
             )),
         )(input)
     }
 }
 
+fn gen_cluster_block<'a, 'b>(
+    b: &'a ClusterBlock,
+) -> impl Fn((&'b mut [u8], usize)) -> GenResult<'b> + 'a {
+    move |input| match b {
+        ClusterBlock::SimpleBlock(data) => gen_ebml_binary(0xA3, data)(input),
+        ClusterBlock::BlockGroup(group) => gen_block_group(group)(input),
+    }
+}
+
+impl<'a> EbmlSize for BlockGroup<'a> {
+    fn capacity(&self) -> usize {
+        let reference_priority = match self.reference_priority {
+            0 => 0,
+            p => p.size(0xFA),
+        };
+
+        self.block.size(0xA1)
+            + self.block_duration.size(0x9B)
+            + reference_priority
+            + self.reference_block.size(0xFB)
+            + self.codec_state.size(0xA4)
+            + self.discard_padding.size(0x75A2)
+    }
+}
+
+fn gen_block_group<'a, 'b>(
+    g: &'a BlockGroup,
+) -> impl Fn((&'b mut [u8], usize)) -> GenResult<'b> + 'a {
+    move |input| {
+        let byte_capacity = vint_size(g.capacity() as u64)?;
+        // The ReferencePriority is left out when it has its default value
+        let reference_priority = Some(g.reference_priority).filter(|&p| p != 0);
+
+        gen_ebml_master(
+            0xA0,
+            byte_capacity,
+            tuple((
+                gen_ebml_binary(0xA1, g.block),
+                gen_opt_copy(g.block_duration, |v| gen_ebml_uint(0x9B, v)),
+                gen_opt_copy(reference_priority, |v| gen_ebml_uint(0xFA, v)),
+                gen_opt_copy(g.reference_block, |v| gen_ebml_int(0xFB, v)),
+                gen_opt(g.codec_state.as_ref(), |v| gen_ebml_binary(0xA4, v)),
+                gen_opt_copy(g.discard_padding, |v| gen_ebml_int(0x75A2, v)),
+            )),
+        )(input)
+    }
+}
+
+impl EbmlSize for Cues {
+    fn capacity(&self) -> usize {
+        self.cue_points
+            .iter()
+            .fold(0, |acc, point| acc + point.size(0xBB))
+    }
+}
+
+pub(crate) fn gen_cues<'a, 'b>(
+    c: &'a Cues,
+) -> impl Fn((&'b mut [u8], usize)) -> GenResult<'b> + 'a {
+    move |input| {
+        let byte_capacity = vint_size(c.capacity() as u64)?;
+        gen_ebml_master(
+            0x1C53BB6B,
+            byte_capacity,
+            gen_many(&c.cue_points, gen_cue_point),
+        )(input)
+    }
+}
+
+impl EbmlSize for CuePoint {
+    fn capacity(&self) -> usize {
+        self.time.size(0xB3)
+            + self
+                .track_positions
+                .iter()
+                .fold(0, |acc, pos| acc + pos.size(0xB7))
+    }
+}
+
+fn gen_cue_point<'a, 'b>(c: &'a CuePoint) -> impl Fn((&'b mut [u8], usize)) -> GenResult<'b> + 'a {
+    move |input| {
+        let byte_capacity = vint_size(c.capacity() as u64)?;
+        gen_ebml_master(
+            0xBB,
+            byte_capacity,
+            tuple((
+                gen_ebml_uint(0xB3, c.time),
+                gen_many(&c.track_positions, gen_cue_track_positions),
+            )),
+        )(input)
+    }
+}
+
+impl EbmlSize for CueTrackPositions {
+    fn capacity(&self) -> usize {
+        self.track.size(0xF7)
+            + self.cluster_position.size(0xF1)
+            + self.relative_position.size(0xF0)
+            + self.duration.size(0xB2)
+            + self.block_number.size(0x5378)
+            + self.references.iter().fold(0, |acc, r| acc + r.size(0xDB))
+    }
+}
+
+fn gen_cue_track_positions<'a, 'b>(
+    c: &'a CueTrackPositions,
+) -> impl Fn((&'b mut [u8], usize)) -> GenResult<'b> + 'a {
+    move |input| {
+        let byte_capacity = vint_size(c.capacity() as u64)?;
+        gen_ebml_master(
+            0xB7,
+            byte_capacity,
+            tuple((
+                gen_ebml_uint(0xF7, c.track),
+                gen_ebml_uint(0xF1, c.cluster_position),
+                gen_opt_copy(c.relative_position, |v| gen_ebml_uint(0xF0, v)),
+                gen_opt_copy(c.duration, |v| gen_ebml_uint(0xB2, v)),
+                gen_opt_copy(c.block_number, |v| gen_ebml_uint(0x5378, v)),
+                gen_many(&c.references, gen_cue_reference),
+            )),
+        )(input)
+    }
+}
+
+impl EbmlSize for CueReference {
+    fn capacity(&self) -> usize {
+        self.ref_time.size(0x96)
+    }
+}
+
+fn gen_cue_reference<'a, 'b>(
+    c: &'a CueReference,
+) -> impl Fn((&'b mut [u8], usize)) -> GenResult<'b> + 'a {
+    move |input| {
+        let byte_capacity = vint_size(c.capacity() as u64)?;
+        gen_ebml_master(0xDB, byte_capacity, gen_ebml_uint(0x96, c.ref_time))(input)
+    }
+}
+
 pub(crate) fn gen_simple_block_header<'a, 'b>(
     s: &'a SimpleBlock,
 ) -> impl Fn((&'b mut [u8], usize)) -> Result<(&'b mut [u8], usize), GenError> + 'a {
@@ -430,22 +614,22 @@ pub(crate) fn gen_simple_block_header<'a, 'b>(
         let mut flags = 0u8;
 
         if s.keyframe {
-            flags |= 0b0001u8;
+            flags |= 0b10000000u8;
         }
 
         if s.invisible {
-            flags |= 0b00010000u8;
+            flags |= 0b00001000u8;
         }
 
         flags |= match s.lacing {
             Lacing::None => 0u8,
-            Lacing::Xiph => 0b00100000u8,
-            Lacing::FixedSize => 0b01000000u8,
-            Lacing::EBML => 0b01100000u8,
+            Lacing::Xiph => 0b00000010u8,
+            Lacing::FixedSize => 0b00000100u8,
+            Lacing::EBML => 0b00000110u8,
         };
 
         if s.discardable {
-            flags |= 0b10000000u8;
+            flags |= 0b00000001u8;
         }
 
         set_be_u8(
@@ -455,46 +639,120 @@ pub(crate) fn gen_simple_block_header<'a, 'b>(
     }
 }
 
-#[allow(dead_code)]
-fn gen_laced_frames<'a>(
+/// Writes the frames of a Block with the given lacing, without the Block header.
+///
+/// A Block holds up to 256 laced frames, and a single frame without lacing.
+pub(crate) fn gen_laced_frames<'a>(
     input: (&'a mut [u8], usize),
     lacing: Lacing,
     frames: &[&[u8]],
 ) -> Result<(&'a mut [u8], usize), GenError> {
+    match (lacing, frames) {
+        (Lacing::None, [frame]) => gen_slice(frame)(input),
+        (Lacing::None, _) => Err(GenError::CustomError(0)),
+        (_, []) => Err(GenError::CustomError(0)),
+        (_, frames) if frames.len() > 256 => Err(GenError::CustomError(0)),
+        (Lacing::Xiph, frames) => gen_xiph_laced_frames(input, frames),
+        (Lacing::EBML, frames) => gen_ebml_laced_frames(input, frames),
+        (Lacing::FixedSize, frames) => gen_fixed_size_laced_frames(input, frames),
+    }
+}
+
+/// Computes the size of the lacing header of `frames`, or `None` if
+/// the lacing cannot store them.
+pub(crate) fn lacing_size(lacing: Lacing, frames: &[&[u8]]) -> Option<usize> {
+    let (last, sizes) = frames.split_last()?;
+
     match lacing {
-        Lacing::None => Err(GenError::NotYetImplemented),
-        Lacing::Xiph => gen_xiph_laced_frames(input, frames),
-        Lacing::EBML => gen_ebml_laced_frames(input, frames),
-        Lacing::FixedSize => gen_fixed_size_laced_frames(input, frames),
+        Lacing::None => sizes.is_empty().then_some(0),
+        _ if frames.len() > 256 => None,
+        Lacing::Xiph => Some(1 + sizes.iter().map(|f| f.len() / 255 + 1).sum::<usize>()),
+        Lacing::EBML => {
+            let first = vint_size(frames[0].len() as u64).ok()? as usize;
+            let differences = frames[..sizes.len()]
+                .windows(2)
+                .map(|w| signed_vint_size(w[1].len() as i64 - w[0].len() as i64))
+                .sum::<Option<usize>>()?;
+            Some(1 + if sizes.is_empty() { 0 } else { first } + differences)
+        }
+        Lacing::FixedSize => sizes.iter().all(|f| f.len() == last.len()).then_some(1),
     }
 }
 
-#[allow(dead_code)]
+fn gen_lace_count<'a>(
+    input: (&'a mut [u8], usize),
+    frames: &[&[u8]],
+) -> Result<(&'a mut [u8], usize), GenError> {
+    set_be_u8(input, (frames.len() - 1) as u8)
+}
+
+fn gen_frames<'a>(
+    input: (&'a mut [u8], usize),
+    frames: &[&[u8]],
+) -> Result<(&'a mut [u8], usize), GenError> {
+    gen_many(frames, |f| gen_slice(f))(input)
+}
+
+// Every size but the last one is stored as a sum of bytes, ending with one below 255
 fn gen_xiph_laced_frames<'a>(
-    _input: (&'a mut [u8], usize),
+    input: (&'a mut [u8], usize),
     frames: &[&[u8]],
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    if frames.is_empty() {
-        return Err(GenError::NotYetImplemented);
+    let mut input = gen_lace_count(input, frames)?;
+
+    for frame in &frames[..frames.len() - 1] {
+        let mut size = frame.len();
+        while size >= 255 {
+            input = set_be_u8(input, 255)?;
+            size -= 255;
+        }
+        input = set_be_u8(input, size as u8)?;
     }
 
-    Err(GenError::NotYetImplemented)
+    gen_frames(input, frames)
 }
 
-#[allow(dead_code)]
+// The first size is a vint, the following ones but the last are signed differences
 fn gen_ebml_laced_frames<'a>(
-    _input: (&'a mut [u8], usize),
-    _frames: &[&[u8]],
+    input: (&'a mut [u8], usize),
+    frames: &[&[u8]],
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    Err(GenError::NotYetImplemented)
+    let mut input = gen_lace_count(input, frames)?;
+
+    let sizes = &frames[..frames.len() - 1];
+    if let Some(first) = sizes.first() {
+        input = gen_vint(first.len() as u64)(input)?;
+    }
+    for w in sizes.windows(2) {
+        input = gen_signed_vint(w[1].len() as i64 - w[0].len() as i64)(input)?;
+    }
+
+    gen_frames(input, frames)
 }
 
-#[allow(dead_code)]
 fn gen_fixed_size_laced_frames<'a>(
-    _input: (&'a mut [u8], usize),
-    _frames: &[&[u8]],
+    input: (&'a mut [u8], usize),
+    frames: &[&[u8]],
 ) -> Result<(&'a mut [u8], usize), GenError> {
-    Err(GenError::NotYetImplemented)
+    if frames.iter().any(|f| f.len() != frames[0].len()) {
+        return Err(GenError::CustomError(0));
+    }
+
+    let input = gen_lace_count(input, frames)?;
+    gen_frames(input, frames)
+}
+
+// Signed vints are stored with a bias of half their range, all ones being reserved
+fn signed_vint_size(num: i64) -> Option<usize> {
+    (1..=8).find(|&len| num.unsigned_abs() < 1 << (7 * len - 1))
+}
+
+fn gen_signed_vint(num: i64) -> impl Fn((&mut [u8], usize)) -> GenResult<'_> {
+    move |input| {
+        let len = signed_vint_size(num).ok_or(GenError::CustomError(0))?;
+        let bias = (1i64 << (7 * len - 1)) - 1;
+        gen_vint_l((num + bias) as u64, len as u8)(input)
+    }
 }
 
 #[cfg(test)]
@@ -562,4 +820,139 @@ mod tests {
         TestResult::from_bool(test_seek_head_serializer(seeks))
       }
     }
+
+    #[test]
+    fn cues_serializer() {
+        let cues = Cues {
+            cue_points: vec![CuePoint {
+                time: 2000,
+                track_positions: vec![CueTrackPositions {
+                    track: 1,
+                    cluster_position: 4096,
+                    relative_position: Some(12),
+                    duration: Some(40),
+                    block_number: None,
+                    codec_state: 0,
+                    references: vec![CueReference { ref_time: 1960 }],
+                }],
+            }],
+        };
+
+        let mut data = vec![0; cues.size(0x1C53BB6B)];
+        gen_cues(&cues)((&mut data[..], 0)).unwrap();
+
+        match crate::elements::segment_element(&data[..]) {
+            Ok((rest, SegmentElement::Cues(c))) => {
+                assert!(rest.is_empty());
+                assert_eq!(c, cues);
+            }
+            e => panic!("parse error: {e:?}"),
+        }
+    }
+
+    #[test]
+    fn seek_head_reserved() {
+        let seek_head = SeekHead {
+            positions: vec![Seek {
+                id: [0x15, 0x49, 0xA9, 0x66],
+                position: 96,
+            }],
+        };
+        let size = seek_head.size(0x114D9B74);
+
+        // A single byte left is absorbed by a wider size
+        for reserved in [size, size + 1, size + 2, size + 200] {
+            let mut data = vec![0xFF; reserved];
+            let (_, len) =
+                gen_seek_head_reserved(&seek_head, reserved)((&mut data[..], 0)).unwrap();
+            assert_eq!(len, reserved);
+
+            match crate::elements::segment_element(&data[..]) {
+                Ok((rest, SegmentElement::SeekHead(s))) => {
+                    assert_eq!(s, seek_head);
+                    if !rest.is_empty() {
+                        match crate::elements::segment_element(rest) {
+                            Ok((rest, SegmentElement::Void(_))) => assert!(rest.is_empty()),
+                            e => panic!("parse error: {e:?}"),
+                        }
+                    }
+                }
+                e => panic!("parse error: {e:?}"),
+            }
+        }
+
+        assert!(gen_seek_head_reserved(&seek_head, size - 1)((&mut [0; 64][..], 0)).is_err());
+    }
+
+    #[test]
+    fn lacing_serializer() {
+        let frame = |size: usize| (0..size).map(|n| n as u8).collect::<Vec<_>>();
+        let unequal = [frame(300), frame(12), frame(600), frame(0), frame(70)];
+        let equal = [frame(40), frame(40), frame(40)];
+
+        for (lacing, frames, header_size) in [
+            (Lacing::Xiph, &unequal[..], 1 + 2 + 1 + 3 + 1),
+            (Lacing::EBML, &unequal[..], 1 + 2 + 2 + 2 + 2),
+            (Lacing::Xiph, &equal[..], 1 + 1 + 1),
+            (Lacing::EBML, &equal[..], 1 + 1 + 1),
+            (Lacing::FixedSize, &equal[..], 1),
+            (Lacing::None, &equal[..1], 0),
+        ] {
+            let frames = frames.iter().map(|f| &f[..]).collect::<Vec<_>>();
+            let frames_size = frames.iter().map(|f| f.len()).sum::<usize>();
+            assert_eq!(lacing_size(lacing, &frames), Some(header_size));
+
+            let mut data = vec![0; header_size + frames_size];
+            let (_, len) = gen_laced_frames((&mut data[..], 0), lacing, &frames).unwrap();
+            assert_eq!(len, data.len());
+
+            let (_, parsed) = crate::elements::laced_frames(&lacing, &data).unwrap();
+            assert_eq!(parsed, frames);
+        }
+
+        let unequal = unequal.iter().map(|f| &f[..]).collect::<Vec<_>>();
+        assert_eq!(lacing_size(Lacing::FixedSize, &unequal), None);
+        assert_eq!(lacing_size(Lacing::None, &unequal), None);
+        let mut data = vec![0; 2048];
+        assert!(gen_laced_frames((&mut data[..], 0), Lacing::FixedSize, &unequal).is_err());
+        assert!(gen_laced_frames((&mut data[..], 0), Lacing::Xiph, &[]).is_err());
+    }
+
+    #[test]
+    fn cluster_serializer() {
+        // Track 1, relative timestamps 0, 20 and 40, keyframe then interframes
+        let simple_block = [0x81, 0x00, 0x00, 0x80, 0xAA];
+        let block = [0x81, 0x00, 0x14, 0x00, 0xBB, 0xCC];
+        let last_block = [0x81, 0x00, 0x28, 0x00, 0xDD];
+
+        // The Blocks keep their order
+        let cluster = Cluster {
+            timestamp: 1000,
+            position: None,
+            prev_size: None,
+            blocks: vec![
+                ClusterBlock::SimpleBlock(&simple_block),
+                ClusterBlock::BlockGroup(BlockGroup {
+                    block: &block,
+                    block_duration: Some(40),
+                    reference_priority: 0,
+                    reference_block: Some(-20),
+                    codec_state: None,
+                    discard_padding: Some(-3500000),
+                }),
+                ClusterBlock::SimpleBlock(&last_block),
+            ],
+        };
+
+        let mut data = vec![0; cluster.size(0x1F43B675)];
+        gen_cluster(&cluster)((&mut data[..], 0)).unwrap();
+
+        match crate::elements::segment_element(&data[..]) {
+            Ok((rest, SegmentElement::Cluster(c))) => {
+                assert!(rest.is_empty());
+                assert_eq!(c, cluster);
+            }
+            e => panic!("parse error: {e:?}"),
+        }
+    }
 }
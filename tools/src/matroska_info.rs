@@ -304,8 +304,33 @@ fn run(filename: &str) -> Result<(), InfoError> {
                     println!("|+   Timestamp: {}", c.timestamp);
                     println!("|+   Position: {:?}", c.position);
                     println!("|+   Prev size: {:?}", c.prev_size);
-                    println!("|+   Simple block: {} elements", c.simple_block.len());
-                    println!("|+   Block group: {} elements", c.block_group.len());
+                    println!("|+   Simple block: {} elements", c.simple_blocks().count());
+                    println!("|+   Block group: {} elements", c.block_groups().count());
+                }
+                SegmentElement::Cues(c) => {
+                    println!("|+ Cues");
+                    println!("|+   Cue points: {} elements", c.cue_points.len());
+                }
+                SegmentElement::Chapters(c) => {
+                    println!("|+ Chapters");
+                    println!("|+   Edition entries: {} elements", c.editions.len());
+                }
+                SegmentElement::Attachments(a) => {
+                    println!("|+ Attachments");
+                    for f in a.files.iter() {
+                        println!("|+   Attached file: {} ({})", f.name, f.media_type);
+                        println!("|+     File UID: {}", f.uid);
+                        println!("|+     File data: size {}", f.data.len());
+                    }
+                }
+                SegmentElement::Tags(t) => {
+                    println!("|+ Tags");
+                    for tag in t.tags.iter() {
+                        println!("|+   Tag (target type: {})", tag.targets.target_type_value);
+                        for st in tag.simple_tags.iter() {
+                            println!("|+     {}: {}", st.name, st.string.as_deref().unwrap_or(""));
+                        }
+                    }
                 }
                 SegmentElement::Void(s) => {
                     println!("|+ EbmlVoid (size: {})", s);